@@ -24,13 +24,19 @@ fn communicate(ipc: &mut IPC, msg: IPCMsg) {
         }
     }
 
-    let m: IPCMsg;
-    match ipc.receive_msg() {
-        Ok(msg) => m = msg,
-        Err(msg) => {
-            error!("{}", msg);
-            process::exit(1);
+    let mut m: IPCMsg;
+    loop {
+        match ipc.receive_msg() {
+            Ok(msg) => m = msg,
+            Err(msg) => {
+                error!("{}", msg);
+                process::exit(1);
+            }
+        }
+        if m.typ != IPCMsgType::More {
+            break;
         }
+        print!("{}", str::from_utf8(&m.msg).unwrap().trim_end_matches('\0'));
     }
 
     let response = str::from_utf8(&m.msg).unwrap();
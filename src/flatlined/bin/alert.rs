@@ -0,0 +1,818 @@
+use flatconf::FlatConf;
+use sink::{self, AlertSink, RateLimit, Route};
+use stats::{HostState, Statistic, Transition};
+use std::collections::HashMap;
+use std::fmt;
+use std::mem;
+use std::time::Duration;
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertKind {
+    /// A host went down or is still down when re-notifying.
+    Down,
+    /// A host that was down beats again.
+    Recovery,
+}
+
+impl fmt::Display for AlertKind {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(match *self {
+            AlertKind::Down => "down",
+            AlertKind::Recovery => "recovery",
+        })
+    }
+}
+
+/// How urgent an alert is, the severity configured for the host and
+/// critical by default. Recoveries share it with the down alert they
+/// resolve, so they are routed to the same sinks.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertSeverity {
+    Info,
+    Warning,
+    Critical,
+}
+
+impl fmt::Display for AlertSeverity {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(match *self {
+            AlertSeverity::Info => "info",
+            AlertSeverity::Warning => "warning",
+            AlertSeverity::Critical => "critical",
+        })
+    }
+}
+
+/// Everything known about a host when an alert is raised for it.
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct Alert {
+    #[serde(rename = "alert")]
+    pub kind: AlertKind,
+    /// Identity of the host, its node ID or address.
+    pub host: String,
+    pub address: String,
+    pub node_id: Option<String>,
+    pub state: HostState,
+    pub previous_state: Option<HostState>,
+    /// Timestamp of the last beat, in seconds since the epoch.
+    pub last_seen: u64,
+    /// Seconds the host has been down, the whole outage for recoveries.
+    pub downtime: u64,
+    pub send_beats: u64,
+    pub recv_beats: u64,
+    pub rejected_beats: u64,
+    pub severity: AlertSeverity,
+    pub tags: Vec<String>,
+}
+
+impl Alert {
+    pub fn new(kind: AlertKind, stat: &Statistic, now: Duration) -> Alert {
+        let downtime = match (kind, stat.last_transition) {
+            (AlertKind::Recovery, Some(t)) => t.duration,
+            (AlertKind::Down, _) if stat.state == HostState::Down => stat.time_in_state(now),
+            _ => Duration::from_secs(0),
+        };
+        Alert {
+            kind,
+            host: stat.server.id().to_string(),
+            address: stat.server.address.clone(),
+            node_id: stat.server.node_id.clone(),
+            state: stat.state,
+            previous_state: stat.last_transition.map(|t| t.from),
+            last_seen: stat.last_seen.as_secs(),
+            downtime: downtime.as_secs(),
+            send_beats: stat.send_beats,
+            recv_beats: stat.recv_beats,
+            rejected_beats: stat.rejected_beats,
+            severity: stat.server.severity.unwrap_or(AlertSeverity::Critical),
+            tags: stat.server.tags.clone().unwrap_or_default(),
+        }
+    }
+
+    /// Names and values describing the alert. They are exported to the
+    /// alert command as `FLAT_<NAME>` and replace `{name}` in its template.
+    pub fn variables(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("alert", self.kind.to_string()),
+            ("host", self.host.clone()),
+            ("address", self.address.clone()),
+            ("node_id", self.node_id.clone().unwrap_or_default()),
+            ("state", self.state.to_string()),
+            (
+                "previous_state",
+                self.previous_state.map(|s| s.to_string()).unwrap_or_default(),
+            ),
+            ("last_seen", self.last_seen.to_string()),
+            ("downtime", self.downtime.to_string()),
+            ("tx", self.send_beats.to_string()),
+            ("rx", self.recv_beats.to_string()),
+            ("rejected", self.rejected_beats.to_string()),
+            ("severity", self.severity.to_string()),
+            ("tags", self.tags.join(",")),
+        ]
+    }
+
+    /// Replaces the `{name}` placeholders in a command with the shell
+    /// quoted values of the alert. Unknown placeholders are left alone.
+    pub fn expand(&self, template: &str) -> String {
+        substitute(&self.variables(), template, shell_quote)
+    }
+
+    /// Replaces the `{name}` placeholders in a text with the values of the
+    /// alert as they are.
+    pub fn render(&self, template: &str) -> String {
+        substitute(&self.variables(), template, |v| v.to_string())
+    }
+}
+
+/// Alerts of one kind raised within the digest window, delivered as a
+/// single notification.
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct Digest {
+    #[serde(rename = "digest")]
+    pub kind: AlertKind,
+    pub count: usize,
+    pub alerts: Vec<Alert>,
+}
+
+impl Digest {
+    /// Groups alerts of the same kind, there must be at least one.
+    pub fn new(alerts: Vec<Alert>) -> Digest {
+        Digest {
+            kind: alerts[0].kind,
+            count: alerts.len(),
+            alerts,
+        }
+    }
+
+    /// A single alert standing in for the whole digest, for sinks without a
+    /// digest format of their own. Hosts, addresses and tags are listed,
+    /// beats summed up, and the highest severity and longest downtime taken.
+    pub fn summary(&self) -> Alert {
+        let first = &self.alerts[0];
+        let list = |field: fn(&Alert) -> &str| {
+            self.alerts.iter().map(field).collect::<Vec<&str>>().join(",")
+        };
+        let mut tags: Vec<String> = Vec::new();
+        for t in self.alerts.iter().flat_map(|a| a.tags.iter()) {
+            if !tags.contains(t) {
+                tags.push(t.clone());
+            }
+        }
+        let previous_state = if self.alerts.iter().all(
+            |a| a.previous_state == first.previous_state,
+        )
+        {
+            first.previous_state
+        } else {
+            None
+        };
+        Alert {
+            kind: self.kind,
+            host: list(|a| &a.host),
+            address: list(|a| &a.address),
+            node_id: None,
+            state: first.state,
+            previous_state,
+            last_seen: self.alerts.iter().map(|a| a.last_seen).max().unwrap_or(0),
+            downtime: self.alerts.iter().map(|a| a.downtime).max().unwrap_or(0),
+            send_beats: self.alerts.iter().map(|a| a.send_beats).sum(),
+            recv_beats: self.alerts.iter().map(|a| a.recv_beats).sum(),
+            rejected_beats: self.alerts.iter().map(|a| a.rejected_beats).sum(),
+            severity: self.alerts.iter().map(|a| a.severity).max().unwrap_or(
+                first.severity,
+            ),
+            tags,
+        }
+    }
+
+    /// The variables of the summary, with the number of alerts as `count`.
+    pub fn variables(&self) -> Vec<(&'static str, String)> {
+        let mut variables = self.summary().variables();
+        variables.push(("count", self.count.to_string()));
+        variables
+    }
+
+    pub fn expand(&self, template: &str) -> String {
+        substitute(&self.variables(), template, shell_quote)
+    }
+
+    pub fn render(&self, template: &str) -> String {
+        substitute(&self.variables(), template, |v| v.to_string())
+    }
+}
+
+/// What a sink delivers: a single alert or a digest of several. Both are
+/// sent as they are, a digest carrying a `digest` field in place of
+/// `alert`.
+#[derive(Debug, Serialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Notification {
+    Alert(Alert),
+    Digest(Digest),
+}
+
+impl fmt::Display for Notification {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Notification::Alert(ref a) => write!(fmt, "{} alert for {}", a.kind, a.host),
+            Notification::Digest(ref d) => write!(fmt, "{} digest of {} hosts", d.kind, d.count),
+        }
+    }
+}
+
+/// Replaces the `{name}` placeholders in a single pass over the template.
+/// Values are never scanned again, so placeholders in them stay as they are.
+fn substitute<F: Fn(&str) -> String>(
+    variables: &[(&str, String)],
+    template: &str,
+    quote: F,
+) -> String {
+    let mut text = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        text.push_str(&rest[..open]);
+        rest = &rest[open..];
+        let value = rest.find('}').and_then(|close| {
+            variables
+                .iter()
+                .find(|v| v.0 == &rest[1..close])
+                .map(|v| (close, &v.1))
+        });
+        match value {
+            Some((close, value)) => {
+                text.push_str(&quote(value));
+                rest = &rest[close + 1..];
+            }
+            None => {
+                text.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    text.push_str(rest);
+    text
+}
+
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// Decides when a host is worth an alert and hands it to the sinks.
+///
+/// Alerts are raised on state transitions only: once when a host goes down
+/// and once when it recovers. While a host stays down the down alert is
+/// repeated every `renotify` seconds, if configured. An alert of the same
+/// kind as the last one sent or held back for a host since its last
+/// transition is a duplicate and suppressed, unless it is such a reminder.
+///
+/// Without routes every sink gets every alert. Otherwise an alert goes to
+/// the sinks of all routes matching it.
+///
+/// With `digest_window` set, alerts are held back for that many seconds
+/// after the first of them, and whatever piled up by then goes to each
+/// sink as one digest per kind. Sinks with a `rate_limit` get at most that
+/// many notifications a minute, a digest counting as one, and drop the
+/// rest.
+pub struct Alerter {
+    renotify: Option<Duration>,
+    /// Local time of the last down alert per host, since the epoch.
+    last_alert: HashMap<String, Duration>,
+    /// Kind of the last alert delivered per host since its last transition,
+    /// and the local time it was fired at.
+    last_sent: HashMap<String, (AlertKind, Duration)>,
+    sinks: Vec<Box<dyn AlertSink>>,
+    routes: Vec<Route>,
+    limits: HashMap<String, RateLimit>,
+    digest_window: Option<Duration>,
+    /// Alerts held back for the next digest with the time they were fired
+    /// at, and when the first came in.
+    pending: Vec<(Alert, Duration)>,
+    window_start: Duration,
+}
+
+impl Alerter {
+    pub fn new(conf: &FlatConf) -> Result<Alerter, String> {
+        let sinks = sink::sinks(conf)?;
+        let limits = sinks
+            .iter()
+            .filter_map(|s| {
+                sink::rate_limit(conf, s.name()).map(|l| (s.name().to_string(), RateLimit::new(l)))
+            })
+            .collect();
+        Ok(Alerter {
+            renotify: conf.renotify.map(Duration::from_secs),
+            last_alert: HashMap::new(),
+            last_sent: HashMap::new(),
+            sinks,
+            routes: conf.route.clone().unwrap_or_default(),
+            limits,
+            digest_window: conf.digest_window.filter(|w| *w > 0).map(Duration::from_secs),
+            pending: Vec::new(),
+            window_start: Duration::from_secs(0),
+        })
+    }
+
+    /// Returns the alert due for a host at the given local time, given the
+    /// transition it just went through, if any.
+    pub fn check(
+        &mut self,
+        stat: &Statistic,
+        transition: Option<Transition>,
+        now: Duration,
+    ) -> Option<AlertKind> {
+        let id = stat.server.id().to_string();
+        // a transition is news, whatever was delivered before it
+        match transition.map(|t| t.to) {
+            Some(HostState::Down) => {
+                self.last_sent.remove(&id);
+                self.last_alert.insert(id, now);
+                return Some(AlertKind::Down);
+            }
+            Some(HostState::Recovered) => {
+                self.last_sent.remove(&id);
+                self.last_alert.remove(&id);
+                return Some(AlertKind::Recovery);
+            }
+            _ => (),
+        }
+        if stat.state != HostState::Down {
+            return None;
+        }
+        let renotify = self.renotify?;
+        let last = self.last_alert.entry(id).or_insert(now);
+        if now.checked_sub(*last).unwrap_or_default() >= renotify {
+            *last = now;
+            Some(AlertKind::Down)
+        } else {
+            None
+        }
+    }
+
+    /// Whether an alert repeats the last one held back for the next digest
+    /// or delivered for its host before a reminder is due.
+    fn duplicate(&self, alert: &Alert, now: Duration) -> bool {
+        let last = match self.pending.iter().rev().find(|p| p.0.host == alert.host) {
+            Some(&(ref a, at)) => Some((a.kind, at)),
+            None => self.last_sent.get(&alert.host).cloned(),
+        };
+        if let Some((kind, at)) = last {
+            let recent = match self.renotify {
+                Some(r) => now.checked_sub(at).unwrap_or_default() < r,
+                None => true,
+            };
+            if kind == alert.kind && recent {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Whether an alert is routed to the named sink.
+    fn routes_to(&self, alert: &Alert, sink: &str) -> bool {
+        self.routes.is_empty() ||
+            self.routes.iter().any(|r| {
+                r.matches(alert) && r.sinks.iter().any(|s| s == sink)
+            })
+    }
+
+    /// Hands an alert to the sinks it is routed to, or holds it back for
+    /// the next digest. Returns false if it was suppressed as a duplicate.
+    pub fn fire(&mut self, alert: &Alert, now: Duration) -> bool {
+        if self.duplicate(alert, now) {
+            debug!("Suppressing duplicate {} alert for {}", alert.kind, alert.host);
+            return false;
+        }
+        if self.digest_window.is_none() {
+            self.deliver(&[(alert.clone(), now)], now);
+            return true;
+        }
+        if self.pending.is_empty() {
+            self.window_start = now;
+        }
+        self.pending.push((alert.clone(), now));
+        true
+    }
+
+    /// Sends the alerts held back once the digest window has passed.
+    pub fn flush(&mut self, now: Duration) {
+        let window = match self.digest_window {
+            Some(w) => w,
+            None => return,
+        };
+        let elapsed = now.checked_sub(self.window_start).unwrap_or_default();
+        if self.pending.is_empty() || elapsed < window {
+            return;
+        }
+        let alerts = mem::take(&mut self.pending);
+        self.deliver(&alerts, now);
+    }
+
+    /// Sends alerts, along with the time they were fired at, to their sinks
+    /// and records those that reached at least one of them. Alerts dropped
+    /// everywhere are not duplicates later on.
+    fn deliver(&mut self, alerts: &[(Alert, Duration)], now: Duration) {
+        let mut delivered = Vec::new();
+        for (a, _) in alerts {
+            if !self.routes.is_empty() && !self.routes.iter().any(|r| r.matches(a)) {
+                warn!("No route for {} alert of {}", a.kind, a.host);
+            }
+        }
+        for s in &self.sinks {
+            for kind in &[AlertKind::Down, AlertKind::Recovery] {
+                let group: Vec<&(Alert, Duration)> = alerts
+                    .iter()
+                    .filter(|p| p.0.kind == *kind && self.routes_to(&p.0, s.name()))
+                    .collect();
+                if group.is_empty() {
+                    continue;
+                }
+                if let Some(limit) = self.limits.get_mut(s.name()) {
+                    if !limit.allow(now) {
+                        warn!(
+                            "Rate limit of {} reached, dropping {} {} alerts",
+                            s.name(),
+                            group.len(),
+                            kind
+                        );
+                        continue;
+                    }
+                }
+                delivered.extend(group.iter().map(|p| (p.0.host.clone(), (p.0.kind, p.1))));
+                if group.len() == 1 {
+                    s.send(&group[0].0);
+                } else {
+                    s.send_digest(&Digest::new(group.iter().map(|p| p.0.clone()).collect()));
+                }
+            }
+        }
+        self.last_sent.extend(delivered);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::{env, fs, process, thread};
+    use testutil::{conf, server};
+
+    fn alerter(renotify: Option<u64>) -> Alerter {
+        let mut conf = conf("");
+        conf.renotify = renotify;
+        Alerter::new(&conf).unwrap()
+    }
+
+    struct Recorder {
+        name: String,
+        received: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl AlertSink for Recorder {
+        fn name(&self) -> &str {
+            &self.name
+        }
+
+        fn send(&self, alert: &Alert) {
+            self.received.lock().unwrap().push(
+                format!("{} {}", self.name, alert.host),
+            );
+        }
+    }
+
+    fn record(a: &mut Alerter, names: Vec<&str>) -> Arc<Mutex<Vec<String>>> {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        a.sinks = names
+            .into_iter()
+            .map(|n| {
+                Box::new(Recorder {
+                    name: n.to_string(),
+                    received: received.clone(),
+                }) as Box<dyn AlertSink>
+            })
+            .collect();
+        received
+    }
+
+    fn host(name: &str) -> Statistic {
+        let mut s = stat();
+        s.server.node_id = Some(name.to_string());
+        s.state = HostState::Down;
+        s
+    }
+
+    fn stat() -> Statistic {
+        Statistic::new(&server())
+    }
+
+    fn enter(stat: &mut Statistic, state: HostState, secs: u64) -> Option<Transition> {
+        let t = Transition {
+            from: stat.state,
+            to: state,
+            at: Duration::from_secs(secs),
+            duration: Duration::from_secs(0),
+        };
+        stat.state = state;
+        Some(t)
+    }
+
+    #[test]
+    fn transition_test() {
+        let mut a = alerter(None);
+        let mut s = stat();
+
+        let t = enter(&mut s, HostState::Up, 1);
+        assert_eq!(a.check(&s, t, Duration::from_secs(1)), None);
+        let t = enter(&mut s, HostState::Down, 2);
+        assert_eq!(a.check(&s, t, Duration::from_secs(2)), Some(AlertKind::Down));
+        for i in 3..100 {
+            assert_eq!(a.check(&s, None, Duration::from_secs(i)), None);
+        }
+        let t = enter(&mut s, HostState::Recovered, 100);
+        assert_eq!(a.check(&s, t, Duration::from_secs(100)), Some(AlertKind::Recovery));
+        let t = enter(&mut s, HostState::Up, 160);
+        assert_eq!(a.check(&s, t, Duration::from_secs(160)), None);
+    }
+
+    #[test]
+    fn renotify_test() {
+        let mut a = alerter(Some(30));
+        let mut s = stat();
+
+        let t = enter(&mut s, HostState::Down, 0);
+        assert_eq!(a.check(&s, t, Duration::from_secs(0)), Some(AlertKind::Down));
+        assert_eq!(a.check(&s, None, Duration::from_secs(29)), None);
+        assert_eq!(a.check(&s, None, Duration::from_secs(30)), Some(AlertKind::Down));
+        assert_eq!(a.check(&s, None, Duration::from_secs(31)), None);
+        assert_eq!(a.check(&s, None, Duration::from_secs(60)), Some(AlertKind::Down));
+
+        let t = enter(&mut s, HostState::Recovered, 70);
+        assert_eq!(a.check(&s, t, Duration::from_secs(70)), Some(AlertKind::Recovery));
+        assert_eq!(a.check(&s, None, Duration::from_secs(100)), None);
+    }
+
+    #[test]
+    fn context_test() {
+        let mut s = stat();
+        s.server.node_id = Some("web1".to_string());
+        s.set_last_seen(Duration::from_secs(1000));
+        s.incr_recv();
+        s.state = HostState::Recovered;
+        s.last_transition = Some(Transition {
+            from: HostState::Down,
+            to: HostState::Recovered,
+            at: Duration::from_secs(1200),
+            duration: Duration::from_secs(180),
+        });
+        let alert = Alert::new(AlertKind::Recovery, &s, Duration::from_secs(1200));
+
+        assert_eq!(alert.host, "web1");
+        assert_eq!(alert.previous_state, Some(HostState::Down));
+        assert_eq!(alert.downtime, 180);
+        assert_eq!(
+            alert.expand("page.sh {host} {state} {downtime} {unknown}"),
+            "page.sh 'web1' 'RECOVERED' '180' {unknown}"
+        );
+        assert_eq!(alert.render("{host} is {state}"), "web1 is RECOVERED");
+        assert!(alert.variables().contains(&("address", "10.0.0.1".to_string())));
+    }
+
+    #[test]
+    fn quote_test() {
+        let mut s = stat();
+        s.server.node_id = Some("a'; rm -rf /".to_string());
+        let alert = Alert::new(AlertKind::Down, &s, Duration::from_secs(0));
+
+        assert_eq!(alert.expand("echo {host}"), "echo 'a'\\''; rm -rf /'");
+    }
+
+    #[test]
+    fn placeholder_in_value_test() {
+        let mut s = stat();
+        s.server.node_id = Some("$(id){node_id}'".to_string());
+        let alert = Alert::new(AlertKind::Down, &s, Duration::from_secs(0));
+
+        assert_eq!(
+            alert.expand("page.sh {host} {address}"),
+            "page.sh '$(id){node_id}'\\''' '10.0.0.1'"
+        );
+        assert_eq!(alert.render("{host} at {address}"), "$(id){node_id}' at 10.0.0.1");
+        assert_eq!(alert.render("{{host}} {unknown}"), "{$(id){node_id}'} {unknown}");
+    }
+
+    #[test]
+    fn environment_test() {
+        let dir = env::temp_dir().join(format!("flat_alert_{}", process::id()));
+        let mut a = Alerter::new(&conf(&format!(
+            "command = 'echo $FLAT_ALERT $FLAT_HOST $FLAT_STATE > {}'",
+            dir.display()
+        ))).unwrap();
+        let mut s = stat();
+        s.state = HostState::Down;
+
+        a.fire(&Alert::new(AlertKind::Down, &s, Duration::from_secs(0)), Duration::from_secs(0));
+        let mut output = String::new();
+        for _ in 0..50 {
+            output = fs::read_to_string(&dir).unwrap_or_default();
+            if output.ends_with('\n') {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        fs::remove_file(&dir).unwrap();
+        assert_eq!(output, "down 10.0.0.1 DOWN\n");
+    }
+
+    #[test]
+    fn routing_test() {
+        let mut a = Alerter::new(&conf(
+            "command = 'true' \n \
+             [[sink]] \n name = 'web-team' \n type = 'log' \n \
+             [[sink]] \n name = 'ops' \n type = 'log' \n \
+             [[route]] \n tags = ['web'] \n sinks = ['web-team'] \n \
+             [[route]] \n severities = ['critical'] \n sinks = ['ops'] \n",
+        )).unwrap();
+        let received = record(&mut a, vec!["command", "web-team", "ops"]);
+
+        let mut web = stat();
+        web.server.node_id = Some("web1".to_string());
+        web.server.tags = Some(vec!["web".to_string()]);
+        a.fire(&Alert::new(AlertKind::Down, &web, Duration::from_secs(0)), Duration::from_secs(0));
+        a.fire(&Alert::new(AlertKind::Recovery, &web, Duration::from_secs(0)), Duration::from_secs(0));
+        let mut dev = stat();
+        dev.server.node_id = Some("dev1".to_string());
+        dev.server.severity = Some(AlertSeverity::Warning);
+        a.fire(&Alert::new(AlertKind::Down, &dev, Duration::from_secs(0)), Duration::from_secs(0));
+
+        assert_eq!(
+            *received.lock().unwrap(),
+            vec!["web-team web1", "ops web1", "web-team web1", "ops web1"]
+        );
+    }
+
+    #[test]
+    fn duplicate_test() {
+        let mut a = alerter(Some(60));
+        let received = record(&mut a, vec!["pager"]);
+        let down = Alert::new(AlertKind::Down, &host("web1"), Duration::from_secs(0));
+        let up = Alert::new(AlertKind::Recovery, &host("web1"), Duration::from_secs(0));
+
+        a.fire(&down, Duration::from_secs(0));
+        a.fire(&down, Duration::from_secs(10));
+        a.fire(&Alert::new(AlertKind::Down, &host("web2"), Duration::from_secs(0)), Duration::from_secs(10));
+        // a reminder once renotify has passed
+        a.fire(&down, Duration::from_secs(60));
+        a.fire(&up, Duration::from_secs(70));
+        a.fire(&up, Duration::from_secs(80));
+        a.fire(&down, Duration::from_secs(90));
+
+        assert_eq!(
+            *received.lock().unwrap(),
+            vec!["pager web1", "pager web2", "pager web1", "pager web1", "pager web1"]
+        );
+    }
+
+    #[test]
+    fn digest_test() {
+        let mut a = Alerter::new(&conf(
+            "digest_window = 10 \n \
+             [[sink]] \n name = 'web-team' \n type = 'log' \n \
+             [[sink]] \n name = 'ops' \n type = 'log' \n \
+             [[route]] \n hosts = ['web*'] \n sinks = ['web-team'] \n \
+             [[route]] \n sinks = ['ops'] \n",
+        )).unwrap();
+        let received = record(&mut a, vec!["web-team", "ops"]);
+
+        a.fire(&Alert::new(AlertKind::Down, &host("web1"), Duration::from_secs(0)), Duration::from_secs(100));
+        a.fire(&Alert::new(AlertKind::Down, &host("web2"), Duration::from_secs(0)), Duration::from_secs(105));
+        a.fire(&Alert::new(AlertKind::Down, &host("db1"), Duration::from_secs(0)), Duration::from_secs(108));
+        a.fire(&Alert::new(AlertKind::Recovery, &host("db2"), Duration::from_secs(0)), Duration::from_secs(109));
+        a.flush(Duration::from_secs(109));
+        assert!(received.lock().unwrap().is_empty());
+
+        a.flush(Duration::from_secs(110));
+        assert_eq!(
+            *received.lock().unwrap(),
+            vec!["web-team web1,web2", "ops web1,web2,db1", "ops db2"]
+        );
+
+        // the next window starts with the next alert
+        a.fire(&Alert::new(AlertKind::Down, &host("db2"), Duration::from_secs(0)), Duration::from_secs(200));
+        a.flush(Duration::from_secs(205));
+        assert_eq!(received.lock().unwrap().len(), 3);
+        a.flush(Duration::from_secs(210));
+        assert_eq!(received.lock().unwrap()[3], "ops db2");
+    }
+
+    #[test]
+    fn summary_test() {
+        let mut web = host("web1");
+        web.server.tags = Some(vec!["web".to_string()]);
+        web.server.severity = Some(AlertSeverity::Warning);
+        web.recv_beats = 10;
+        let mut db = host("db1");
+        db.server.address = "10.0.0.2".to_string();
+        db.server.tags = Some(vec!["db".to_string(), "web".to_string()]);
+        db.recv_beats = 5;
+        let digest = Digest::new(vec![
+            Alert::new(AlertKind::Down, &web, Duration::from_secs(0)),
+            Alert::new(AlertKind::Down, &db, Duration::from_secs(0)),
+        ]);
+        let summary = digest.summary();
+
+        assert_eq!(summary.host, "web1,db1");
+        assert_eq!(summary.address, "10.0.0.1,10.0.0.2");
+        assert_eq!(summary.recv_beats, 15);
+        assert_eq!(summary.severity, AlertSeverity::Critical);
+        assert_eq!(summary.tags, vec!["web", "db"]);
+        assert_eq!(
+            digest.expand("page.sh {count} {host} {state}"),
+            "page.sh '2' 'web1,db1' 'DOWN'"
+        );
+    }
+
+    #[test]
+    fn rate_limit_test() {
+        let mut a = Alerter::new(&conf(
+            "rate_limit = 2 \n \
+             [[sink]] \n name = 'pager' \n type = 'log' \n rate_limit = 1 \n \
+             [[sink]] \n name = 'chat' \n type = 'log' \n",
+        )).unwrap();
+        let received = record(&mut a, vec!["pager", "chat"]);
+
+        for (i, h) in ["web1", "web2", "web3"].iter().enumerate() {
+            let at = Duration::from_secs(i as u64);
+            a.fire(&Alert::new(AlertKind::Down, &host(h), at), at);
+        }
+        a.fire(&Alert::new(AlertKind::Down, &host("web4"), Duration::from_secs(60)), Duration::from_secs(60));
+
+        assert_eq!(
+            *received.lock().unwrap(),
+            vec!["pager web1", "chat web1", "chat web2", "pager web4", "chat web4"]
+        );
+    }
+
+    #[test]
+    fn dropped_test() {
+        let mut a = Alerter::new(&conf(
+            "[[sink]] \n name = 'pager' \n type = 'log' \n rate_limit = 1 \n",
+        )).unwrap();
+        let received = record(&mut a, vec!["pager"]);
+        let down = Alert::new(AlertKind::Down, &host("web2"), Duration::from_secs(0));
+
+        a.fire(&Alert::new(AlertKind::Down, &host("web1"), Duration::from_secs(0)), Duration::from_secs(0));
+        assert!(a.fire(&down, Duration::from_secs(1)));
+        // never delivered, so not a duplicate once the limit allows it
+        assert!(a.fire(&down, Duration::from_secs(60)));
+        assert!(!a.fire(&down, Duration::from_secs(70)));
+
+        assert_eq!(*received.lock().unwrap(), vec!["pager web1", "pager web2"]);
+    }
+
+    /// Feeds a host through a transition, or none, at the given time and
+    /// fires the alert due, as the listener does.
+    fn advance(a: &mut Alerter, s: &mut Statistic, state: HostState, secs: u64) -> bool {
+        let now = Duration::from_secs(secs);
+        let transition = s.enter(state, now);
+        match a.check(s, transition, now) {
+            Some(kind) => a.fire(&Alert::new(kind, s, now), now),
+            None => false,
+        }
+    }
+
+    #[test]
+    fn dropped_recovery_test() {
+        let mut a = Alerter::new(&conf(
+            "[[sink]] \n name = 'pager' \n type = 'log' \n rate_limit = 1 \n",
+        )).unwrap();
+        let received = record(&mut a, vec!["pager"]);
+        let mut s = stat();
+
+        assert!(advance(&mut a, &mut s, HostState::Down, 0));
+        // the recovery is dropped by the rate limit
+        assert!(advance(&mut a, &mut s, HostState::Recovered, 10));
+        // the next outage is news all the same
+        assert!(advance(&mut a, &mut s, HostState::Down, 100));
+
+        assert_eq!(*received.lock().unwrap(), vec!["pager 10.0.0.1", "pager 10.0.0.1"]);
+    }
+
+    #[test]
+    fn digest_renotify_test() {
+        let mut c = conf("digest_window = 10 \n");
+        c.renotify = Some(30);
+        let mut a = Alerter::new(&c).unwrap();
+        let received = record(&mut a, vec!["pager"]);
+        let mut s = stat();
+
+        assert!(advance(&mut a, &mut s, HostState::Down, 0));
+        a.flush(Duration::from_secs(10));
+        // the reminder is due 30 s after the alert was fired, not delivered
+        assert!(advance(&mut a, &mut s, HostState::Down, 30));
+        a.flush(Duration::from_secs(40));
+
+        assert_eq!(*received.lock().unwrap(), vec!["pager 10.0.0.1", "pager 10.0.0.1"]);
+    }
+}
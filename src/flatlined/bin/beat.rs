@@ -3,33 +3,163 @@ extern crate quickcheck;
 use std::time::*;
 use blake2_rfc::blake2b::Blake2b;
 use constant_time_eq::constant_time_eq;
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use chacha20poly1305::{ChaCha20Poly1305, Key, KeyInit, Nonce};
+use chacha20poly1305::aead::{Aead, Payload};
+use rand;
+use keys;
 use std::str;
+use std::cmp;
 
 static DEFAULT_MSG: &'static str = "beat";
 
+/// Magic bytes at the start of every versioned beat frame.
+pub const MAGIC: [u8; 2] = [0x46, 0x4c];
+/// Version of the beat frames created by this daemon.
+pub const VERSION: u8 = 1;
+/// Pseudo version of the bare 72 byte beats of older daemons.
+pub const LEGACY_VERSION: u8 = 0;
+/// Size of a legacy beat: 8 byte timestamp and 64 byte checksum.
+pub const LEGACY_SIZE: usize = 72;
+/// Longest key older daemons authenticated legacy beats with. They used the
+/// key directly and fell back to an empty key for anything longer.
+pub const MAX_LEGACY_KEY_SIZE: usize = 63;
+/// Largest beat that fits into a single unfragmented UDP datagram.
+pub const MAX_BEAT_SIZE: usize = 1472;
+
+/// Flag marking beats whose trailer is an Ed25519 signature instead of a
+/// keyed BLAKE2b checksum.
+pub const FLAG_SIGNED: u8 = 0x01;
+/// Flag marking acknowledgements sent back by a listening server.
+pub const FLAG_ACK: u8 = 0x02;
+
+/// Extension carrying the sender nonce and sequence number, 8 bytes each.
+pub const EXT_SEQUENCE: u8 = 1;
+/// Extension carrying further extensions encrypted with ChaCha20-Poly1305:
+/// a 12 byte nonce followed by the ciphertext and tag.
+pub const EXT_SEALED: u8 = 2;
+/// Extension carrying the UTF-8 node ID of the sender. It is never sealed so
+/// the receiver knows who sent a beat before decrypting it.
+pub const EXT_NODE_ID: u8 = 3;
+/// Extension carrying the UTF-8 ID of the key the beat was authenticated
+/// with. Never sealed either.
+pub const EXT_KEY_ID: u8 = 4;
+/// Extension carrying the sub-second part of the timestamp in nanoseconds,
+/// 4 bytes.
+pub const EXT_NANOS: u8 = 5;
+/// Extension carrying the health status of the sender, see `status::Status`.
+pub const EXT_STATUS: u8 = 6;
+/// Extension carrying the nonce and sequence number of the acknowledged
+/// beat, 8 bytes each.
+pub const EXT_ACK: u8 = 7;
+
+const HEADER_SIZE: usize = 6;
+const HASH_SIZE: usize = 64;
+const NONCE_SIZE: usize = 12;
+const MIN_BEAT_SIZE: usize = HEADER_SIZE + 8 + HASH_SIZE;
+
+/// Frame layout (all integers little endian):
+///
+/// ```text
+/// | magic (2) | version (1) | flags (1) | length (2) | timestamp (8) |
+/// | extensions (type (1), length (2), value)* | checksum (64) |
+/// ```
+///
+/// `length` is the size of the whole frame. The checksum covers every
+/// byte before it, so extensions are authenticated as well. Receivers skip
+/// extension types they do not know. Beats with `FLAG_SIGNED` set carry an
+/// Ed25519 signature over the same bytes in place of the checksum.
 pub struct Beat {
+    pub version: u8,
+    pub flags: u8,
     pub timestamp: u64,
+    pub extensions: Vec<Extension>,
     hash: [u8; 64],
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub struct Extension {
+    pub typ: u8,
+    pub value: Vec<u8>,
+}
+
+impl Extension {
+    fn pair(typ: u8, first: u64, second: u64) -> Extension {
+        let mut value = u64_to_u8arr(first).to_vec();
+        value.extend_from_slice(&u64_to_u8arr(second));
+        Extension { typ, value }
+    }
+
+    /// Creates the sequence extension. `nonce` identifies a sender session,
+    /// `seq` increases with every beat sent during that session.
+    pub fn sequence(nonce: u64, seq: u64) -> Extension {
+        Extension::pair(EXT_SEQUENCE, nonce, seq)
+    }
+
+    /// Creates the extension of an acknowledgement for the beat with the
+    /// given nonce and sequence number.
+    pub fn ack(nonce: u64, seq: u64) -> Extension {
+        Extension::pair(EXT_ACK, nonce, seq)
+    }
+
+    pub fn node_id(node_id: &str) -> Extension {
+        Extension {
+            typ: EXT_NODE_ID,
+            value: node_id.as_bytes().to_vec(),
+        }
+    }
+
+    pub fn nanos(nanos: u32) -> Extension {
+        Extension {
+            typ: EXT_NANOS,
+            value: u32_to_u8arr(nanos).to_vec(),
+        }
+    }
+
+    pub fn key_id(key_id: &str) -> Extension {
+        Extension {
+            typ: EXT_KEY_ID,
+            value: key_id.as_bytes().to_vec(),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum BeatError {
     WrongSize,
     ListenError,
     SendError,
     WrongChecksum,
+    WrongMagic,
+    UnknownVersion,
+    Malformed,
+    LegacyRejected,
+    Replayed,
+    Stale,
+    ClockSkew,
+    DecryptionFailed,
+    NotEncrypted,
+    Revoked,
+    Timeout,
+    NoKey,
 }
 
 impl PartialEq for Beat {
     fn eq(&self, other: &Beat) -> bool {
-        self.timestamp == other.timestamp && constant_time_eq(&self.hash, &other.hash)
+        self.version == other.version && self.flags == other.flags &&
+            self.timestamp == other.timestamp &&
+            self.extensions == other.extensions &&
+            constant_time_eq(&self.hash, &other.hash)
     }
 }
 
 impl Clone for Beat {
     fn clone(&self) -> Beat {
         let mut b = Beat {
+            version: self.version,
+            flags: self.flags,
             timestamp: self.timestamp,
+            extensions: self.extensions.clone(),
             hash: [0; 64],
         };
         b.hash.clone_from_slice(&self.hash);
@@ -37,7 +167,7 @@ impl Clone for Beat {
     }
 }
 
-fn u64_to_u8arr(value: u64) -> [u8; 8] {
+pub fn u64_to_u8arr(value: u64) -> [u8; 8] {
     let mut ret: [u8; 8] = [0, 0, 0, 0, 0, 0, 0, 0];
     ret[0] |= (value & 0xFF) as u8;
     ret[1] |= ((value & (0xFF << 8)) >> 8) as u8;
@@ -50,7 +180,7 @@ fn u64_to_u8arr(value: u64) -> [u8; 8] {
     ret
 }
 
-fn u8arr_to_u64(value: [u8; 8]) -> u64 {
+pub fn u8arr_to_u64(value: [u8; 8]) -> u64 {
     let mut ret = (value[7] as u64) << 56;
     ret |= (value[6] as u64) << 48;
     ret |= (value[5] as u64) << 40;
@@ -61,18 +191,47 @@ fn u8arr_to_u64(value: [u8; 8]) -> u64 {
     ret | value[0] as u64
 }
 
+pub fn u32_to_u8arr(value: u32) -> [u8; 4] {
+    [
+        (value & 0xFF) as u8,
+        ((value & (0xFF << 8)) >> 8) as u8,
+        ((value & (0xFF << 16)) >> 16) as u8,
+        ((value & (0xFF << 24)) >> 24) as u8,
+    ]
+}
+
+pub fn u8arr_to_u32(value: [u8; 4]) -> u32 {
+    (value[3] as u32) << 24 | (value[2] as u32) << 16 | (value[1] as u32) << 8 | value[0] as u32
+}
+
+fn u16_to_u8arr(value: u16) -> [u8; 2] {
+    [(value & 0xFF) as u8, ((value & 0xFF00) >> 8) as u8]
+}
+
+fn u8arr_to_u16(value: [u8; 2]) -> u16 {
+    (value[1] as u16) << 8 | value[0] as u16
+}
+
+/// Encodes extensions as a sequence of type, length and value.
+pub fn encode_extensions(extensions: &[Extension]) -> Vec<u8> {
+    let mut ret = Vec::new();
+    for e in extensions {
+        ret.push(e.typ);
+        ret.extend_from_slice(&u16_to_u8arr(e.value.len() as u16));
+        ret.extend_from_slice(&e.value);
+    }
+    ret
+}
+
 impl Beat {
-    fn create_timestamp() -> u64 {
-        SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs()
+    fn create_timestamp() -> Duration {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
     }
 
-    fn create_checksum(key: &str, time: &[u8]) -> [u8; 64] {
-        let mut ctx = Blake2b::with_key(64, key.as_bytes());
+    fn create_checksum(key: &[u8], data: &[u8]) -> [u8; 64] {
+        let mut ctx = Blake2b::with_key(64, key);
         ctx.update(DEFAULT_MSG.as_bytes());
-        ctx.update(time);
+        ctx.update(data);
         let temp = ctx.finalize();
         let res = temp.as_bytes();
         let mut ret: [u8; 64] = [0; 64];
@@ -82,22 +241,249 @@ impl Beat {
         ret
     }
 
+    #[allow(dead_code)]
     pub fn new(server_key: &str) -> Beat {
-        let time = Beat::create_timestamp();
-        let key = if server_key.is_empty() || server_key.len() >= 64 {
-            ""
-        } else {
-            server_key
-        };
+        Beat::with_extensions(server_key, Vec::new())
+    }
 
-        let hash = Beat::create_checksum(key, &(u64_to_u8arr(time)));
+    /// Creates a beat without checksum. It has to be finished with either
+    /// `authenticate` or `sign` before it is sent. The sub-second part of
+    /// the timestamp is added as the first extension.
+    pub fn unsigned(extensions: Vec<Extension>) -> Beat {
+        let now = Beat::create_timestamp();
+        let mut all = vec![Extension::nanos(now.subsec_nanos())];
+        all.extend(extensions);
         Beat {
-            timestamp: time,
-            hash: hash,
+            version: VERSION,
+            flags: 0,
+            timestamp: now.as_secs(),
+            extensions: all,
+            hash: [0; 64],
         }
     }
 
-    pub fn from_bytes(data: [u8; 72]) -> Beat {
+    /// Creates a beat signed with the client's Ed25519 key.
+    #[allow(dead_code)]
+    pub fn with_signature(signing_key: &SigningKey, extensions: Vec<Extension>) -> Beat {
+        let mut beat = Beat::unsigned(extensions);
+        beat.sign(signing_key);
+        beat
+    }
+
+    /// Creates an unsigned acknowledgement for the beat with the given nonce
+    /// and sequence number.
+    pub fn acknowledgement(nonce: u64, seq: u64) -> Beat {
+        let mut beat = Beat::unsigned(vec![Extension::ack(nonce, seq)]);
+        beat.flags |= FLAG_ACK;
+        beat
+    }
+
+    pub fn with_extensions(server_key: &str, extensions: Vec<Extension>) -> Beat {
+        let mut beat = Beat::unsigned(extensions);
+        beat.authenticate(server_key);
+        beat
+    }
+
+    /// Adds the keyed BLAKE2b checksum, keyed with a key derived from the
+    /// shared key.
+    pub fn authenticate(&mut self, server_key: &str) {
+        self.flags &= !FLAG_SIGNED;
+        self.hash = Beat::create_checksum(&*keys::mac_key(server_key), &self.signed_bytes());
+    }
+
+    /// Adds the Ed25519 signature.
+    pub fn sign(&mut self, signing_key: &SigningKey) {
+        self.flags |= FLAG_SIGNED;
+        self.hash = signing_key.sign(&self.signed_bytes()).to_bytes();
+    }
+
+    /// Additional data bound to the encrypted extensions: everything in the
+    /// header that is fixed once the beat is created.
+    fn sealing_aad(&self) -> Vec<u8> {
+        let mut ret = MAGIC.to_vec();
+        ret.push(self.version);
+        ret.extend_from_slice(&u64_to_u8arr(self.timestamp));
+        ret
+    }
+
+    /// Encrypts `extensions` with ChaCha20-Poly1305 and appends them as a
+    /// single `EXT_SEALED` extension. Has to be called before the beat is
+    /// authenticated or signed.
+    pub fn seal(&mut self, key: &[u8; 32], extensions: Vec<Extension>) {
+        let nonce: [u8; NONCE_SIZE] = rand::random();
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+        let payload = Payload {
+            msg: &encode_extensions(&extensions),
+            aad: &self.sealing_aad(),
+        };
+        let mut value = nonce.to_vec();
+        value.extend(cipher.encrypt(Nonce::from_slice(&nonce), payload).unwrap());
+        self.extensions.push(Extension {
+            typ: EXT_SEALED,
+            value,
+        });
+    }
+
+    /// Decrypts the sealed extensions and replaces the `EXT_SEALED`
+    /// extension with them. Only call this on verified beats, the checksum
+    /// does not match anymore afterwards.
+    pub fn open(&mut self, key: &[u8; 32]) -> Result<(), BeatError> {
+        let pos = match self.extensions.iter().position(|e| e.typ == EXT_SEALED) {
+            Some(pos) => pos,
+            None => return Ok(()),
+        };
+        let plain = {
+            let sealed = &self.extensions[pos].value;
+            if sealed.len() < NONCE_SIZE {
+                return Err(BeatError::Malformed);
+            }
+            let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+            let payload = Payload {
+                msg: &sealed[NONCE_SIZE..],
+                aad: &self.sealing_aad(),
+            };
+            match cipher.decrypt(Nonce::from_slice(&sealed[..NONCE_SIZE]), payload) {
+                Ok(plain) => Beat::parse_extensions(&plain)?,
+                Err(_) => return Err(BeatError::DecryptionFailed),
+            }
+        };
+        self.extensions.remove(pos);
+        self.extensions.extend(plain);
+        Ok(())
+    }
+
+    /// Time the beat was sent at, since the epoch. Beats without sub-second
+    /// part only have a resolution of seconds.
+    pub fn time(&self) -> Duration {
+        Duration::new(self.timestamp, self.nanos())
+    }
+
+    /// Returns the sub-second part of the timestamp in nanoseconds.
+    pub fn nanos(&self) -> u32 {
+        match self.extension(EXT_NANOS) {
+            Some(e) if e.value.len() == 4 => {
+                let mut nanos = [0u8; 4];
+                nanos[..].clone_from_slice(&e.value);
+                match u8arr_to_u32(nanos) {
+                    n if n < 1_000_000_000 => n,
+                    _ => 0,
+                }
+            }
+            _ => 0,
+        }
+    }
+
+    /// Milliseconds the sender's clock is ahead (positive) or behind
+    /// (negative) the local clock, ignoring the transit time of the beat.
+    pub fn clock_offset(&self) -> i64 {
+        let now = Beat::create_timestamp();
+        let time = self.time();
+        if time >= now {
+            cmp::min((time - now).as_millis(), i64::MAX as u128) as i64
+        } else {
+            -(cmp::min((now - time).as_millis(), i64::MAX as u128) as i64)
+        }
+    }
+
+    /// Rejects beats whose timestamp is more than `max_skew` seconds away
+    /// from the local clock.
+    pub fn check_skew(&self, max_skew: u64) -> Result<(), BeatError> {
+        if self.clock_offset().unsigned_abs() > max_skew.saturating_mul(1000) {
+            Err(BeatError::ClockSkew)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns the first extension of the given type.
+    pub fn extension(&self, typ: u8) -> Option<&Extension> {
+        self.extensions.iter().find(|e| e.typ == typ)
+    }
+
+    /// Returns the `(nonce, sequence)` pair if the beat carries one.
+    pub fn sequence(&self) -> Option<(u64, u64)> {
+        self.pair_extension(EXT_SEQUENCE)
+    }
+
+    /// Returns the `(nonce, sequence)` pair of the beat an acknowledgement
+    /// refers to.
+    pub fn acknowledged(&self) -> Option<(u64, u64)> {
+        if !self.is_ack() {
+            return None;
+        }
+        self.pair_extension(EXT_ACK)
+    }
+
+    fn pair_extension(&self, typ: u8) -> Option<(u64, u64)> {
+        match self.extension(typ) {
+            Some(e) if e.value.len() == 16 => {
+                let mut nonce = [0u8; 8];
+                let mut seq = [0u8; 8];
+                nonce[..].clone_from_slice(&e.value[..8]);
+                seq[..].clone_from_slice(&e.value[8..]);
+                Some((u8arr_to_u64(nonce), u8arr_to_u64(seq)))
+            }
+            _ => None,
+        }
+    }
+
+    fn string_extension(&self, typ: u8) -> Option<String> {
+        match self.extension(typ) {
+            Some(e) => str::from_utf8(&e.value).ok().map(|n| n.to_string()),
+            None => None,
+        }
+    }
+
+    /// Returns the node ID the sender claims to have.
+    pub fn node_id(&self) -> Option<String> {
+        self.string_extension(EXT_NODE_ID)
+    }
+
+    /// Returns the ID of the key the beat was authenticated with.
+    pub fn key_id(&self) -> Option<String> {
+        self.string_extension(EXT_KEY_ID)
+    }
+
+    pub fn is_sealed(&self) -> bool {
+        self.extension(EXT_SEALED).is_some()
+    }
+
+    pub fn is_ack(&self) -> bool {
+        self.flags & FLAG_ACK != 0
+    }
+
+    pub fn is_signed(&self) -> bool {
+        self.flags & FLAG_SIGNED != 0
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        self.version == LEGACY_VERSION
+    }
+
+    /// Size of the serialized frame without the checksum.
+    fn body_size(&self) -> usize {
+        self.extensions.iter().fold(HEADER_SIZE + 8, |acc, e| {
+            acc + 3 + e.value.len()
+        })
+    }
+
+    /// The bytes covered by the checksum.
+    fn signed_bytes(&self) -> Vec<u8> {
+        if self.is_legacy() {
+            return u64_to_u8arr(self.timestamp).to_vec();
+        }
+
+        let mut ret = Vec::with_capacity(self.body_size() + HASH_SIZE);
+        ret.extend_from_slice(&MAGIC);
+        ret.push(self.version);
+        ret.push(self.flags);
+        ret.extend_from_slice(&u16_to_u8arr((self.body_size() + HASH_SIZE) as u16));
+        ret.extend_from_slice(&u64_to_u8arr(self.timestamp));
+        ret.extend(encode_extensions(&self.extensions));
+        ret
+    }
+
+    fn from_legacy_bytes(data: &[u8]) -> Beat {
         let mut ts = [0u8; 8];
         let mut cs = [0u8; 64];
 
@@ -105,29 +491,104 @@ impl Beat {
         cs[..].clone_from_slice(&data[8..]);
 
         Beat {
+            version: LEGACY_VERSION,
+            flags: 0,
             timestamp: u8arr_to_u64(ts),
+            extensions: Vec::new(),
             hash: cs,
         }
     }
 
-    pub fn into_bytes(self) -> [u8; 72] {
-        let mut ret = [0u8; 72];
-        let ts = u64_to_u8arr(self.timestamp);
+    /// Parses a sequence of extensions as written by `encode_extensions`.
+    pub fn parse_extensions(mut data: &[u8]) -> Result<Vec<Extension>, BeatError> {
+        let mut ret = Vec::new();
+        while !data.is_empty() {
+            if data.len() < 3 {
+                return Err(BeatError::Malformed);
+            }
+            let len = u8arr_to_u16([data[1], data[2]]) as usize;
+            if data.len() < 3 + len {
+                return Err(BeatError::Malformed);
+            }
+            ret.push(Extension {
+                typ: data[0],
+                value: data[3..3 + len].to_vec(),
+            });
+            data = &data[3 + len..];
+        }
+        Ok(ret)
+    }
 
-        ret[..8].clone_from_slice(&ts[..8]);
-        ret[8..].clone_from_slice(&self.hash[..64]);
+    /// Parses a received datagram. Datagrams of exactly `LEGACY_SIZE` bytes
+    /// are treated as legacy beats, everything else has to be a versioned
+    /// frame.
+    pub fn from_bytes(data: &[u8]) -> Result<Beat, BeatError> {
+        if data.len() == LEGACY_SIZE {
+            return Ok(Beat::from_legacy_bytes(data));
+        }
+        if data.len() < MIN_BEAT_SIZE || data.len() > MAX_BEAT_SIZE {
+            return Err(BeatError::WrongSize);
+        }
+        if data[..2] != MAGIC {
+            return Err(BeatError::WrongMagic);
+        }
+        if data[2] != VERSION {
+            return Err(BeatError::UnknownVersion);
+        }
+        if u8arr_to_u16([data[4], data[5]]) as usize != data.len() {
+            return Err(BeatError::WrongSize);
+        }
+
+        let mut ts = [0u8; 8];
+        ts[..].clone_from_slice(&data[HEADER_SIZE..HEADER_SIZE + 8]);
+        let hash_start = data.len() - HASH_SIZE;
+        let mut cs = [0u8; 64];
+        cs[..].clone_from_slice(&data[hash_start..]);
 
+        Ok(Beat {
+            version: data[2],
+            flags: data[3],
+            timestamp: u8arr_to_u64(ts),
+            extensions: Beat::parse_extensions(&data[HEADER_SIZE + 8..hash_start])?,
+            hash: cs,
+        })
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        let mut ret = self.signed_bytes();
+        ret.extend_from_slice(&self.hash);
         ret
     }
 
     pub fn verify_beat(&self, key: &str) -> Result<bool, BeatError> {
-        let sum = Beat::create_checksum(key, &u64_to_u8arr(self.timestamp));
+        if self.is_signed() {
+            return Err(BeatError::WrongChecksum);
+        }
+        let sum = if self.is_legacy() {
+            if key.len() > MAX_LEGACY_KEY_SIZE {
+                return Err(BeatError::WrongChecksum);
+            }
+            Beat::create_checksum(key.as_bytes(), &self.signed_bytes())
+        } else {
+            Beat::create_checksum(&*keys::mac_key(key), &self.signed_bytes())
+        };
         if constant_time_eq(&sum, &self.hash) {
             Ok(true)
         } else {
             Err(BeatError::WrongChecksum)
         }
     }
+
+    pub fn verify_signature(&self, public_key: &VerifyingKey) -> Result<bool, BeatError> {
+        if !self.is_signed() {
+            return Err(BeatError::WrongChecksum);
+        }
+        let signature = Signature::from_bytes(&self.hash);
+        match public_key.verify_strict(&self.signed_bytes(), &signature) {
+            Ok(_) => Ok(true),
+            Err(_) => Err(BeatError::WrongChecksum),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -141,13 +602,59 @@ mod tests {
         fn qc(input: Vec<u8>) -> bool {
             let msg = Beat::new(str::from_utf8(&input).unwrap());
             let bmsg = msg.clone().into_bytes();
-            let nbmsg = Beat::from_bytes(bmsg);
+            let nbmsg = Beat::from_bytes(&bmsg).unwrap();
 
             nbmsg == msg
         }
         quickcheck(qc as fn(Vec<u8>) -> bool);
     }
 
+    #[test]
+    fn sealed_qc_test() {
+        fn qc(input: Vec<u8>, key: Vec<u8>) -> bool {
+            let mut k = [0u8; 32];
+            for (i, v) in key.iter().take(32).enumerate() {
+                k[i] = *v;
+            }
+            let ext = vec![
+                Extension {
+                    typ: 200,
+                    value: input,
+                },
+            ];
+            let mut msg = Beat::unsigned(Vec::new());
+            msg.seal(&k, ext.clone());
+            msg.authenticate("foo");
+            let mut nbmsg = Beat::from_bytes(&msg.clone().into_bytes()).unwrap();
+
+            nbmsg == msg && nbmsg.verify_beat("foo").is_ok() && nbmsg.is_sealed() &&
+                nbmsg.open(&k).is_ok() && nbmsg.extensions[1..] == ext[..]
+        }
+        quickcheck(qc as fn(Vec<u8>, Vec<u8>) -> bool);
+    }
+
+    #[test]
+    fn sealed_wrong_key_test() {
+        let mut msg = Beat::unsigned(Vec::new());
+        msg.seal(&[1u8; 32], vec![Extension::sequence(1, 2)]);
+        msg.authenticate("foo");
+
+        assert!(msg.sequence().is_none());
+        assert!(msg.open(&[2u8; 32]).is_err());
+        assert!(msg.is_sealed());
+        assert!(msg.open(&[1u8; 32]).is_ok());
+        assert_eq!(msg.sequence(), Some((1, 2)));
+    }
+
+    #[test]
+    fn sealed_header_test() {
+        let mut msg = Beat::unsigned(Vec::new());
+        msg.seal(&[1u8; 32], vec![Extension::sequence(1, 2)]);
+        msg.timestamp += 1;
+
+        assert!(msg.open(&[1u8; 32]).is_err());
+    }
+
     #[test]
     fn verification_test() {
         let b = Beat::new("key");
@@ -164,7 +671,7 @@ mod tests {
     fn to_bytes_test() {
         let msg = Beat::new("foo");
         let bmsg = msg.clone().into_bytes();
-        let nbmsg = Beat::from_bytes(bmsg);
+        let nbmsg = Beat::from_bytes(&bmsg).unwrap();
 
         assert!(nbmsg == msg, true);
     }
@@ -172,7 +679,7 @@ mod tests {
     #[test]
     fn from_bytes_test() {
         let ts = u64_to_u8arr(u64::max_value());
-        let hs = Beat::create_checksum("foo", &ts);
+        let hs = Beat::create_checksum(b"foo", &ts);
         let mut data = [0u8; 72];
 
         for b in 0..8 {
@@ -183,34 +690,234 @@ mod tests {
             data[bb + 8] = hs[bb];
         }
 
-        let beat = Beat::from_bytes(data);
+        let beat = Beat::from_bytes(&data).unwrap();
         let bbeat = Beat {
+            version: LEGACY_VERSION,
+            flags: 0,
             timestamp: u64::max_value(),
-            hash: Beat::create_checksum("foo", &ts),
+            extensions: Vec::new(),
+            hash: Beat::create_checksum(b"foo", &ts),
         };
 
         assert!(beat == bbeat, true);
+        assert!(beat.is_legacy());
+        assert!(beat.verify_beat("foo").is_ok());
+    }
+
+    #[test]
+    fn long_key_test() {
+        let long = "x".repeat(200);
+        let beat = Beat::from_bytes(&Beat::new(&long).into_bytes()).unwrap();
+
+        assert!(beat.verify_beat(&long).is_ok());
+        assert!(beat.verify_beat(&"x".repeat(201)).is_err());
+        assert!(beat.verify_beat("").is_err());
+        assert!(Beat::new("").verify_beat("").is_ok());
+    }
+
+    #[test]
+    fn legacy_long_key_test() {
+        let mut data = u64_to_u8arr(1).to_vec();
+        data.extend_from_slice(&Beat::create_checksum(b"", &u64_to_u8arr(1)));
+        let beat = Beat::from_bytes(&data).unwrap();
+
+        // older daemons authenticated with an empty key instead
+        assert!(beat.verify_beat(&"x".repeat(64)).is_err());
+        assert!(beat.verify_beat("").is_ok());
+    }
+
+    #[test]
+    fn extensions_test() {
+        let ext = vec![
+            Extension {
+                typ: 200,
+                value: vec![1, 2, 3],
+            },
+            Extension {
+                typ: 201,
+                value: Vec::new(),
+            },
+        ];
+        let msg = Beat::with_extensions("foo", ext.clone());
+        let bmsg = msg.clone().into_bytes();
+        assert_eq!(bmsg.len(), MIN_BEAT_SIZE + 7 + 3 + 3 + 3);
+
+        let nbmsg = Beat::from_bytes(&bmsg).unwrap();
+        assert!(nbmsg == msg);
+        assert_eq!(nbmsg.extensions[1..], ext[..]);
+        assert!(nbmsg.verify_beat("foo").is_ok());
+    }
+
+    #[test]
+    fn sequence_test() {
+        let msg = Beat::with_extensions("foo", vec![Extension::sequence(42, 7)]);
+        let nbmsg = Beat::from_bytes(&msg.into_bytes()).unwrap();
+
+        assert_eq!(nbmsg.sequence(), Some((42, 7)));
+        assert!(Beat::new("foo").sequence().is_none());
+    }
+
+    #[test]
+    fn ack_test() {
+        let mut msg = Beat::acknowledgement(42, 7);
+        msg.authenticate("foo");
+        let nbmsg = Beat::from_bytes(&msg.into_bytes()).unwrap();
+
+        assert!(nbmsg.is_ack());
+        assert!(nbmsg.verify_beat("foo").is_ok());
+        assert_eq!(nbmsg.acknowledged(), Some((42, 7)));
+        assert!(nbmsg.sequence().is_none());
+
+        // a beat carrying an ack extension without the flag is no ack
+        let beat = Beat::with_extensions("foo", vec![Extension::ack(42, 7)]);
+        assert!(beat.acknowledged().is_none());
+    }
+
+    #[test]
+    fn node_id_test() {
+        let msg = Beat::with_extensions("foo", vec![Extension::node_id("alpha")]);
+        let nbmsg = Beat::from_bytes(&msg.into_bytes()).unwrap();
+
+        assert_eq!(nbmsg.node_id(), Some("alpha".to_string()));
+        assert!(nbmsg.key_id().is_none());
+        assert!(Beat::new("foo").node_id().is_none());
+
+        let msg = Beat::with_extensions("foo", vec![Extension::key_id("2018-06")]);
+        let nbmsg = Beat::from_bytes(&msg.into_bytes()).unwrap();
+        assert_eq!(nbmsg.key_id(), Some("2018-06".to_string()));
+    }
+
+    #[test]
+    fn signature_test() {
+        let key = SigningKey::from_bytes(&[7u8; 32]);
+        let other = SigningKey::from_bytes(&[8u8; 32]);
+        let msg = Beat::with_signature(&key, vec![Extension::sequence(1, 2)]);
+        let nbmsg = Beat::from_bytes(&msg.clone().into_bytes()).unwrap();
+
+        assert!(nbmsg == msg);
+        assert!(nbmsg.is_signed());
+        assert!(nbmsg.verify_signature(&key.verifying_key()).unwrap());
+        assert!(nbmsg.verify_signature(&other.verifying_key()).is_err());
+        assert!(nbmsg.verify_beat("").is_err());
+        assert!(Beat::new("foo").verify_signature(&key.verifying_key()).is_err());
+    }
+
+    #[test]
+    fn tampered_signature_test() {
+        let key = SigningKey::from_bytes(&[7u8; 32]);
+        let mut bmsg = Beat::with_signature(&key, vec![Extension::sequence(1, 2)]).into_bytes();
+        bmsg[HEADER_SIZE + 8 + 3] ^= 0x01;
+
+        let beat = Beat::from_bytes(&bmsg).unwrap();
+        assert!(beat.verify_signature(&key.verifying_key()).is_err());
+    }
 
+    #[test]
+    fn nanos_test() {
+        let b = Beat::from_bytes(&Beat::new("foo").into_bytes()).unwrap();
+        assert_eq!(b.extensions[0].typ, EXT_NANOS);
+        assert_eq!(b.time().as_secs(), b.timestamp);
+        assert_eq!(b.time().subsec_nanos(), b.nanos());
+
+        let mut b = Beat::with_extensions("foo", vec![]);
+        b.extensions[0] = Extension::nanos(250_000_000);
+        assert_eq!(b.time(), Duration::new(b.timestamp, 250_000_000));
+
+        b.extensions[0] = Extension::nanos(1_000_000_000);
+        assert_eq!(b.nanos(), 0);
+
+        b.extensions.clear();
+        assert_eq!(b.time(), Duration::from_secs(b.timestamp));
+    }
+
+    #[test]
+    fn skew_test() {
+        let mut b = Beat::new("foo");
+        assert!(b.clock_offset().abs() <= 1000);
+        assert!(b.check_skew(5).is_ok());
+
+        b.timestamp += 30;
+        assert!(b.clock_offset() >= 29_000);
+        assert!(b.check_skew(5).is_err());
+        assert!(b.check_skew(60).is_ok());
+
+        b.timestamp -= 60;
+        assert!(b.clock_offset() <= -29_000);
+        assert!(b.check_skew(5).is_err());
+
+        b.timestamp = !0;
+        assert!(b.clock_offset() > 0);
+        assert!(b.check_skew(60).is_err());
+
+        b.timestamp = 0;
+        assert!(b.clock_offset() < 0);
+        assert!(b.check_skew(60).is_err());
+    }
+
+    #[test]
+    fn tampered_extension_test() {
+        let ext = vec![
+            Extension {
+                typ: 200,
+                value: vec![1, 2, 3],
+            },
+        ];
+        let mut bmsg = Beat::with_extensions("foo", ext).into_bytes();
+        bmsg[HEADER_SIZE + 8 + 3] ^= 0xFF;
+
+        let beat = Beat::from_bytes(&bmsg).unwrap();
+        assert!(beat.verify_beat("foo").is_err());
+    }
+
+    #[test]
+    fn malformed_frame_test() {
+        let bmsg = Beat::new("foo").into_bytes();
+
+        let mut magic = bmsg.clone();
+        magic[0] = 0;
+        assert!(Beat::from_bytes(&magic).is_err());
+
+        let mut version = bmsg.clone();
+        version[2] = VERSION + 1;
+        assert!(Beat::from_bytes(&version).is_err());
+
+        let mut truncated = bmsg.clone();
+        truncated.pop();
+        assert!(Beat::from_bytes(&truncated).is_err());
+
+        let mut dangling = Beat::new("foo").into_bytes();
+        dangling.insert(HEADER_SIZE + 8, 200);
+        dangling[4] += 1;
+        assert!(Beat::from_bytes(&dangling).is_err());
+
+        assert!(Beat::from_bytes(&[0u8; 10]).is_err());
     }
 
     #[test]
     fn beat_eq_test() {
         let a = Beat::new("foo");
-        let b = Beat::new("foo");
-        assert!(a.timestamp == b.timestamp);
+        let b = a.clone();
         assert!(a == b, true);
+
+        // beats sent within the same second are told apart by their nanos
+        let c = Beat::new("foo");
+        assert!(a.time() < c.time());
+        assert!(a != c);
     }
 
     #[test]
     fn beat_ne_test() {
         let a = Beat {
+            version: LEGACY_VERSION,
+            flags: 0,
             timestamp: 1u64,
-            hash: Beat::create_checksum("foo", &u64_to_u8arr(1u64)),
+            extensions: Vec::new(),
+            hash: Beat::create_checksum(b"foo", &u64_to_u8arr(1u64)),
         };
         let b = Beat::new("foo");
         let c = Beat::new("bar");
         assert!(a != b, true);
-        assert!(a != c, true);
+        assert!(a != c);
     }
 
     #[test]
@@ -226,4 +933,10 @@ mod tests {
         assert_eq!(big, nbi);
         assert_eq!(min, nmi);
     }
+
+    #[test]
+    fn u16to8arrtou16_test() {
+        assert_eq!(u8arr_to_u16(u16_to_u8arr(0xFFFF)), 0xFFFF);
+        assert_eq!(u8arr_to_u16(u16_to_u8arr(0x1234)), 0x1234);
+    }
 }
@@ -0,0 +1,29 @@
+use alert::AlertSeverity;
+use keys::{KeyEntry, Secret};
+
+/// A client known to a listening server.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct Client {
+    /// Node ID the client sends its beats with.
+    pub name: String,
+    /// Shared key used by this client only.
+    pub key: Option<Secret>,
+    /// File the client's shared key is read from.
+    pub key_file: Option<String>,
+    /// Environment variable the client's shared key is read from.
+    pub key_env: Option<String>,
+    /// Rotating keys used by this client only.
+    pub keys: Option<Vec<KeyEntry>>,
+    /// Hex encoded Ed25519 public key the client signs its beats with.
+    pub public_key: Option<String>,
+    /// Beats of revoked clients are rejected.
+    pub revoked: Option<bool>,
+    /// Seconds between two beats of this client.
+    pub interval: Option<u64>,
+    /// Beats this client may miss before it is considered offline.
+    pub missed_beats: Option<u64>,
+    /// Tags alerts for this client can be routed by.
+    pub tags: Option<Vec<String>>,
+    /// Severity of down alerts for this client.
+    pub severity: Option<AlertSeverity>,
+}
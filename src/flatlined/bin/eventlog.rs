@@ -0,0 +1,294 @@
+use alert::{Alert, AlertKind};
+use flatconf::FlatConf;
+use nix::unistd;
+use stats::{HostState, Statistic, Transition};
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+static DEFAULT_SYSLOG_SOCKET: &'static str = "/dev/log";
+static APP_NAME: &'static str = "flatlined";
+/// SD-ID of the structured data, under the example enterprise number of
+/// RFC 5612.
+static SD_ID: &'static str = "flatlined@32473";
+
+/// Facility the records are logged under.
+const FACILITY_DAEMON: u8 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error = 3,
+    Warning = 4,
+    Notice = 5,
+    Info = 6,
+}
+
+enum Output {
+    Syslog(UnixDatagram, PathBuf),
+    File(File),
+    Disabled,
+}
+
+/// Record of host events and alerts for central logging.
+///
+/// Every event is formatted as an RFC 5424 syslog message with the details
+/// as structured data. With `syslog` set the messages go to the local
+/// syslog socket, which journald listens on as well, otherwise they are
+/// appended to `logfile`, one per line. Rejected beats and address changes
+/// are only recorded when `verbose` is set.
+pub struct EventLog {
+    output: Output,
+    hostname: String,
+    verbose: bool,
+}
+
+impl EventLog {
+    pub fn new(conf: &FlatConf) -> Result<EventLog, String> {
+        let logfile = if conf.syslog.unwrap_or(false) {
+            None
+        } else {
+            Some(conf.logfile.as_str())
+        };
+        EventLog::open(logfile, conf)
+    }
+
+    /// An event log appending to a file, or sending to the configured
+    /// syslog socket without one. An empty file name disables it.
+    pub fn open(logfile: Option<&str>, conf: &FlatConf) -> Result<EventLog, String> {
+        let output = match logfile {
+            None => {
+                let path = PathBuf::from(
+                    conf.syslog_socket.clone().unwrap_or_else(
+                        || DEFAULT_SYSLOG_SOCKET.to_string(),
+                    ),
+                );
+                let socket = UnixDatagram::unbound().map_err(|e| e.to_string())?;
+                Output::Syslog(socket, path)
+            }
+            Some("") => Output::Disabled,
+            Some(l) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(l)
+                    .map_err(|e| format!("Could not open {}: {}", l, e))?;
+                Output::File(file)
+            }
+        };
+        let mut buffer = [0u8; 256];
+        let hostname = match unistd::gethostname(&mut buffer) {
+            Ok(h) => h.to_string_lossy().into_owned(),
+            Err(_) => "-".to_string(),
+        };
+        Ok(EventLog {
+            output,
+            hostname,
+            verbose: conf.verbose,
+        })
+    }
+
+    pub fn transition(&mut self, stat: &Statistic, t: &Transition) {
+        let severity = match t.to {
+            HostState::Down => Severity::Error,
+            HostState::Suspect => Severity::Warning,
+            _ => Severity::Notice,
+        };
+        let fields = vec![
+            ("host", stat.server.id().to_string()),
+            ("address", stat.server.address.clone()),
+            ("state", t.to.to_string()),
+            ("previous_state", t.from.to_string()),
+            ("duration", t.duration.as_secs().to_string()),
+        ];
+        let message = format!("{} is {}", stat.server, t.to);
+        self.emit(severity, "transition", &fields, &message);
+    }
+
+    pub fn alert(&mut self, alert: &Alert) {
+        let severity = match alert.kind {
+            AlertKind::Down => Severity::Warning,
+            AlertKind::Recovery => Severity::Notice,
+        };
+        let message = format!("{} alert for {}", alert.kind, alert.host);
+        self.emit(severity, "alert", &alert.variables(), &message);
+    }
+
+    pub fn rejected(&mut self, address: &str, reason: &str) {
+        if self.verbose {
+            let fields = vec![("address", address.to_string()), ("reason", reason.to_string())];
+            let message = format!("Rejected beat from {}", address);
+            self.emit(Severity::Info, "rejected", &fields, &message);
+        }
+    }
+
+    pub fn address_changed(&mut self, stat: &Statistic, address: &str) {
+        if self.verbose {
+            let fields = vec![
+                ("host", stat.server.id().to_string()),
+                ("address", address.to_string()),
+                ("previous_address", stat.server.address.clone()),
+            ];
+            let message = format!("{} changed address to {}", stat.server, address);
+            self.emit(Severity::Info, "address", &fields, &message);
+        }
+    }
+
+    fn emit(&mut self, severity: Severity, msgid: &str, fields: &[(&str, String)], message: &str) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let record = format_record(severity, now, &self.hostname, msgid, fields, message);
+        let result = match self.output {
+            Output::Syslog(ref socket, ref path) => socket.send_to(record.as_bytes(), path).map(|_| ()),
+            Output::File(ref mut file) => writeln!(file, "{}", record),
+            Output::Disabled => Ok(()),
+        };
+        if let Err(e) = result {
+            warn!("Could not log event: {}", e);
+        }
+    }
+}
+
+/// Formats an RFC 5424 syslog message.
+pub fn format_record(
+    severity: Severity,
+    time: Duration,
+    hostname: &str,
+    msgid: &str,
+    fields: &[(&str, String)],
+    message: &str,
+) -> String {
+    let mut data = format!("[{}", SD_ID);
+    for &(name, ref value) in fields {
+        data.push_str(&format!(" {}=\"{}\"", name, escape(value)));
+    }
+    data.push(']');
+    format!(
+        "<{}>1 {} {} {} {} {} {} {}",
+        FACILITY_DAEMON * 8 + severity as u8,
+        timestamp(time),
+        hostname,
+        APP_NAME,
+        unistd::getpid(),
+        msgid,
+        data,
+        message
+    )
+}
+
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == '"' || c == '\\' || c == ']' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// RFC 3339 UTC timestamp with milliseconds.
+fn timestamp(time: Duration) -> String {
+    let secs = time.as_secs();
+    let days = (secs / 86_400) as i64;
+    // civil date from days since the epoch, see
+    // http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs % 86_400 / 3600,
+        secs % 3600 / 60,
+        secs % 60,
+        time.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+    use testutil::conf;
+
+    #[test]
+    fn timestamp_test() {
+        assert_eq!(timestamp(Duration::from_secs(0)), "1970-01-01T00:00:00.000Z");
+        assert_eq!(
+            timestamp(Duration::from_millis(1_709_210_096_789)),
+            "2024-02-29T12:34:56.789Z"
+        );
+    }
+
+    #[test]
+    fn record_test() {
+        let fields = vec![("host", "web1".to_string()), ("reason", "a \"b\" [c]".to_string())];
+        let record = format_record(
+            Severity::Error,
+            Duration::from_secs(0),
+            "mon1",
+            "transition",
+            &fields,
+            "web1 is DOWN",
+        );
+
+        assert_eq!(
+            record,
+            format!(
+                "<27>1 1970-01-01T00:00:00.000Z mon1 flatlined {} transition \
+                 [flatlined@32473 host=\"web1\" reason=\"a \\\"b\\\" [c\\]\"] web1 is DOWN",
+                unistd::getpid()
+            )
+        );
+    }
+
+    #[test]
+    fn file_test() {
+        let path = env::temp_dir().join(format!("flat_events_{}.log", process::id()));
+        let mut c = conf("");
+        c.logfile = path.to_str().unwrap().to_string();
+        let mut events = EventLog::new(&c).unwrap();
+        events.rejected("10.0.0.1", "Replay");
+        events.emit(Severity::Notice, "test", &[], "first");
+        events.emit(Severity::Notice, "test", &[], "second");
+
+        let content = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("<29>1 "));
+        assert!(lines[1].ends_with("[flatlined@32473] second"));
+    }
+
+    #[test]
+    fn syslog_test() {
+        let path = env::temp_dir().join(format!("flat_syslog_{}.sock", process::id()));
+        let _ = fs::remove_file(&path);
+        let server = UnixDatagram::bind(&path).unwrap();
+        let mut c = conf(&format!(
+            "syslog = true \n syslog_socket = '{}' \n",
+            path.display()
+        ));
+        c.verbose = true;
+        let mut events = EventLog::new(&c).unwrap();
+        events.rejected("10.0.0.1", "Replay");
+
+        let mut buffer = [0u8; 1024];
+        let len = server.recv(&mut buffer).unwrap();
+        fs::remove_file(&path).unwrap();
+        let record = String::from_utf8_lossy(&buffer[..len]).into_owned();
+        assert!(record.starts_with("<30>1 "));
+        assert!(record.contains(" rejected [flatlined@32473 address=\"10.0.0.1\" reason=\"Replay\"]"));
+    }
+}
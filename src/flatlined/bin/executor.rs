@@ -0,0 +1,296 @@
+use flatconf::FlatConf;
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
+use std::io::Read;
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Seconds an alert command may run if not configured.
+pub const DEFAULT_ALERT_TIMEOUT: u64 = 30;
+/// Alert commands running at the same time if not configured.
+pub const DEFAULT_ALERT_CONCURRENCY: usize = 4;
+/// Times a failed alert command is retried if not configured.
+pub const DEFAULT_ALERT_RETRIES: u32 = 2;
+
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// Time given to the pipes to close after the processes holding them were
+/// killed.
+const KILL_GRACE: Duration = Duration::from_secs(1);
+
+/// Result of running an alert command, including its retries.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Outcome {
+    pub attempts: u32,
+    /// Exit code of the last attempt, None if it could not be started, was
+    /// killed by a signal or timed out.
+    pub code: Option<i32>,
+    pub timed_out: bool,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl Outcome {
+    pub fn success(&self) -> bool {
+        self.code == Some(0)
+    }
+}
+
+/// Runs alert commands in the background.
+///
+/// Every command gets its own thread that waits for the process, so none
+/// are left as zombies. Commands running longer than the timeout are
+/// killed, at most `concurrency` of them run at once and the rest wait for
+/// a free slot. Processes a command leaves behind in the background are
+/// killed as well once the timeout has passed. Failed commands are retried
+/// and everything they print is logged along with their exit code.
+#[derive(Clone)]
+pub struct Executor {
+    timeout: Duration,
+    concurrency: usize,
+    retries: u32,
+    retry_delay: Duration,
+    running: Arc<(Mutex<usize>, Condvar)>,
+}
+
+impl Executor {
+    pub fn new(conf: &FlatConf) -> Executor {
+        Executor {
+            timeout: Duration::from_secs(conf.alert_timeout.unwrap_or(DEFAULT_ALERT_TIMEOUT)),
+            concurrency: conf.alert_concurrency.unwrap_or(DEFAULT_ALERT_CONCURRENCY),
+            retries: conf.alert_retries.unwrap_or(DEFAULT_ALERT_RETRIES),
+            retry_delay: RETRY_DELAY,
+            running: Arc::new((Mutex::new(0), Condvar::new())),
+        }
+    }
+
+    /// Runs a shell command with additional environment variables.
+    pub fn run(&self, command: String, env: Vec<(String, String)>) -> JoinHandle<Outcome> {
+        let executor = self.clone();
+        thread::spawn(move || {
+            let mut outcome = Outcome::default();
+            loop {
+                outcome = executor.attempt(&command, &env, outcome.attempts + 1);
+                if outcome.success() || outcome.attempts > executor.retries {
+                    break;
+                }
+                warn!(
+                    "Alert command {} failed, retrying in {}s",
+                    command,
+                    executor.retry_delay.as_secs()
+                );
+                thread::sleep(executor.retry_delay);
+            }
+            if !outcome.success() {
+                error!(
+                    "Alert command {} failed after {} attempts",
+                    command,
+                    outcome.attempts
+                );
+            }
+            outcome
+        })
+    }
+
+    fn acquire(&self) -> Slot<'_> {
+        let (ref lock, ref cvar) = *self.running;
+        let mut running = lock.lock().unwrap();
+        while *running >= self.concurrency {
+            running = cvar.wait(running).unwrap();
+        }
+        *running += 1;
+        Slot(self)
+    }
+
+    fn attempt(&self, command: &str, env: &[(String, String)], attempts: u32) -> Outcome {
+        let _slot = self.acquire();
+        let mut outcome = Outcome {
+            attempts,
+            ..Outcome::default()
+        };
+        let child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .envs(env.iter().cloned())
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .process_group(0)
+            .spawn();
+        match child {
+            Ok(c) => self.supervise(c, command, &mut outcome),
+            Err(e) => error!("Could not run alert command {}: {}", command, e),
+        }
+        outcome
+    }
+
+    fn supervise(&self, mut child: Child, command: &str, outcome: &mut Outcome) {
+        let stdout = collect(child.stdout.take());
+        let stderr = collect(child.stderr.take());
+        let started = Instant::now();
+        let group = Pid::from_raw(-(child.id() as i32));
+        let status = loop {
+            match child.try_wait() {
+                Ok(Some(status)) => break Some(status),
+                Ok(None) if started.elapsed() >= self.timeout => {
+                    warn!(
+                        "Alert command {} timed out after {}s, killing it",
+                        command,
+                        self.timeout.as_secs()
+                    );
+                    outcome.timed_out = true;
+                    // the whole group, so nothing the shell started keeps
+                    // running or holds on to the pipes
+                    let _ = signal::kill(group, Signal::SIGKILL);
+                    let _ = child.wait();
+                    break None;
+                }
+                Ok(None) => thread::sleep(POLL_INTERVAL),
+                Err(e) => {
+                    error!("Could not wait for alert command {}: {}", command, e);
+                    break None;
+                }
+            }
+        };
+        outcome.code = status.and_then(|s| s.code());
+        // processes the shell left in the background may still hold the
+        // pipes, they get what is left of the timeout
+        let deadline = started + self.timeout;
+        let mut killed = outcome.timed_out;
+        let mut read = |output: Receiver<String>| {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match output.recv_timeout(left) {
+                Ok(o) => o,
+                Err(_) => {
+                    if !killed {
+                        warn!("Alert command {} left processes running, killing them", command);
+                        let _ = signal::kill(group, Signal::SIGKILL);
+                        killed = true;
+                    }
+                    output.recv_timeout(KILL_GRACE).unwrap_or_default()
+                }
+            }
+        };
+        outcome.stdout = read(stdout);
+        outcome.stderr = read(stderr);
+        for line in outcome.stdout.lines() {
+            info!("{}: {}", command, line);
+        }
+        for line in outcome.stderr.lines() {
+            warn!("{}: {}", command, line);
+        }
+        if let Some(s) = status {
+            info!("Alert command {} exited with {}", command, s);
+        }
+    }
+}
+
+/// One of the `concurrency` slots for running commands, freed when dropped.
+struct Slot<'a>(&'a Executor);
+
+impl<'a> Drop for Slot<'a> {
+    fn drop(&mut self) {
+        let (ref lock, ref cvar) = *self.0.running;
+        *lock.lock().unwrap() -= 1;
+        cvar.notify_one();
+    }
+}
+
+/// Reads a pipe of a child to its end in the background, so a chatty
+/// command can not block on a full pipe.
+fn collect<R: Read + Send + 'static>(pipe: Option<R>) -> Receiver<String> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut output = String::new();
+        if let Some(mut p) = pipe {
+            let mut buffer = Vec::new();
+            let _ = p.read_to_end(&mut buffer);
+            output = String::from_utf8_lossy(&buffer).into_owned();
+        }
+        let _ = tx.send(output);
+    });
+    rx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn executor(timeout: u64, concurrency: usize, retries: u32) -> Executor {
+        Executor {
+            timeout: Duration::from_millis(timeout),
+            concurrency,
+            retries,
+            retry_delay: Duration::from_millis(10),
+            running: Arc::new((Mutex::new(0), Condvar::new())),
+        }
+    }
+
+    #[test]
+    fn output_test() {
+        let e = executor(5000, 1, 0);
+        let env = vec![("FLAT_HOST".to_string(), "web1".to_string())];
+        let outcome = e.run("echo $FLAT_HOST; echo oops >&2".to_string(), env)
+            .join()
+            .unwrap();
+
+        assert!(outcome.success());
+        assert_eq!(outcome.attempts, 1);
+        assert_eq!(outcome.stdout, "web1\n");
+        assert_eq!(outcome.stderr, "oops\n");
+    }
+
+    #[test]
+    fn retry_test() {
+        let e = executor(5000, 1, 2);
+        let outcome = e.run("exit 3".to_string(), Vec::new()).join().unwrap();
+
+        assert_eq!(outcome.attempts, 3);
+        assert_eq!(outcome.code, Some(3));
+        assert!(!outcome.timed_out);
+    }
+
+    #[test]
+    fn timeout_test() {
+        let e = executor(200, 1, 0);
+        let started = Instant::now();
+        let outcome = e.run("sleep 10".to_string(), Vec::new()).join().unwrap();
+
+        assert!(outcome.timed_out);
+        assert_eq!(outcome.code, None);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn background_test() {
+        let e = executor(300, 1, 0);
+        let started = Instant::now();
+        let outcome = e.run("sleep 10 & echo started".to_string(), Vec::new())
+            .join()
+            .unwrap();
+
+        assert!(outcome.success());
+        assert_eq!(outcome.stdout, "started\n");
+        assert!(started.elapsed() < Duration::from_secs(5));
+        // the slot is free for the next command
+        assert_eq!(*e.running.0.lock().unwrap(), 0);
+        assert!(e.run("true".to_string(), Vec::new()).join().unwrap().success());
+    }
+
+    #[test]
+    fn concurrency_test() {
+        let e = executor(5000, 1, 0);
+        let started = Instant::now();
+        let first = e.run("sleep 0.3".to_string(), Vec::new());
+        let second = e.run("sleep 0.3".to_string(), Vec::new());
+
+        assert!(first.join().unwrap().success());
+        assert!(second.join().unwrap().success());
+        assert!(started.elapsed() >= Duration::from_millis(600));
+        assert_eq!(*e.running.0.lock().unwrap(), 0);
+    }
+}
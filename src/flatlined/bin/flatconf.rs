@@ -5,33 +5,254 @@ use std::path::Path;
 use std::io::prelude::*;
 use std::error::Error;
 use std::process;
+use std::time::Duration;
+use phi::PhiDetector;
+use mail::SmtpConf;
+use sink;
+use sink::{Route, SinkConf};
 use server::Server;
+use status;
+use client::Client;
+use beat;
+use keys;
+use keys::{KeyEntry, KeySet, Secret};
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct FlatConf {
     pub port: u16,
     pub logfile: String,
     pub socket: Option<String>,
-    pub key: String,
+    #[serde(default)]
+    pub key: Secret,
+    pub key_file: Option<String>,
+    pub key_env: Option<String>,
     pub verbose: bool,
+    pub syslog: Option<bool>,
+    pub syslog_socket: Option<String>,
     pub server: Option<Vec<Server>>,
     pub command: Option<String>,
+    pub recovery_command: Option<String>,
+    pub renotify: Option<u64>,
+    pub alert_timeout: Option<u64>,
+    pub alert_concurrency: Option<usize>,
+    pub alert_retries: Option<u32>,
+    pub webhook: Option<String>,
+    pub smtp: Option<SmtpConf>,
+    pub sink: Option<Vec<SinkConf>>,
+    pub route: Option<Vec<Route>>,
+    pub digest_window: Option<u64>,
+    pub rate_limit: Option<u32>,
+    pub accept_legacy: Option<bool>,
+    pub max_skew: Option<u64>,
+    pub signing_key: Option<Secret>,
+    pub signing_key_file: Option<String>,
+    pub signing_key_env: Option<String>,
+    pub require_signature: Option<bool>,
+    pub client: Option<Vec<Client>>,
+    pub encryption: Option<String>,
+    pub node_id: Option<String>,
+    pub allow_shared_key: Option<bool>,
+    pub keys: Option<Vec<KeyEntry>>,
+    pub send_status: Option<bool>,
+    pub status: Option<String>,
+    pub status_path: Option<String>,
+    pub ack: Option<bool>,
+    pub ack_timeout: Option<u64>,
+    pub interval: Option<u64>,
+    pub missed_beats: Option<u64>,
+    pub phi_suspect: Option<f64>,
+    pub phi_down: Option<f64>,
 }
 
 pub type ParsingResult = Result<FlatConf, String>;
 
+/// Seconds between two beats if not configured.
+pub const DEFAULT_INTERVAL: u64 = 1;
+/// Beats a host may miss before it is considered offline if not configured.
+pub const DEFAULT_MISSED_BEATS: u64 = 60;
+/// Phi above which a host is suspected to have failed if not configured.
+pub const DEFAULT_PHI_SUSPECT: f64 = 5.0;
+/// Phi above which statistics report a host as likely down if not
+/// configured. Only `missed_beats` actually takes it down.
+pub const DEFAULT_PHI_DOWN: f64 = 8.0;
+
 impl FlatConf {
     pub fn parse(conf: &mut String) -> ParsingResult {
-        let opts: FlatConf;
+        let mut opts: FlatConf;
         match toml::from_str(conf) {
             Ok(conf) => {
                 opts = conf;
+                opts.load_keys()?;
+                opts.validate()?;
                 Ok(opts)
             }
             Err(e) => Err(e.to_string()),
         }
     }
 
+    /// Replaces keys configured through `key_file` or `key_env` with their
+    /// contents.
+    fn load_keys(&mut self) -> Result<(), String> {
+        if let Some(k) = keys::load_key(!self.key.is_empty(), &self.key_file, &self.key_env)? {
+            self.key = k;
+        }
+        if let Some(k) = keys::load_key(
+            self.signing_key.is_some(),
+            &self.signing_key_file,
+            &self.signing_key_env,
+        )?
+        {
+            self.signing_key = Some(k);
+        }
+        load_entries(&mut self.keys)?;
+        let sink_smtp = self.sink.iter_mut().flat_map(|v| v.iter_mut()).filter_map(
+            |s| s.smtp.as_mut(),
+        );
+        for s in self.smtp.iter_mut().chain(sink_smtp) {
+            if let Some(p) = keys::load_key(s.password.is_some(), &s.password_file, &s.password_env)
+                .map_err(|e| format!("SMTP password: {}", e))?
+            {
+                s.password = Some(p);
+            }
+        }
+        for s in self.server.iter_mut().flat_map(|v| v.iter_mut()) {
+            if let Some(k) = keys::load_key(!s.key.is_empty(), &s.key_file, &s.key_env)
+                .map_err(|e| format!("Server {}: {}", s, e))?
+            {
+                s.key = k;
+            }
+            load_entries(&mut s.keys).map_err(
+                |e| format!("Server {}: {}", s, e),
+            )?;
+        }
+        for c in self.client.iter_mut().flat_map(|v| v.iter_mut()) {
+            if let Some(k) = keys::load_key(c.key.is_some(), &c.key_file, &c.key_env)
+                .map_err(|e| format!("Client {}: {}", c.name, e))?
+            {
+                c.key = Some(k);
+            }
+            load_entries(&mut c.keys).map_err(
+                |e| format!("Client {}: {}", c.name, e),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Seconds between two beats, given a host specific setting.
+    pub fn interval(&self, interval: Option<u64>) -> u64 {
+        interval.or(self.interval).unwrap_or(DEFAULT_INTERVAL)
+    }
+
+    /// Beats a host may miss before it is considered offline, given a host
+    /// specific setting.
+    pub fn missed_beats(&self, missed_beats: Option<u64>) -> u64 {
+        missed_beats.or(self.missed_beats).unwrap_or(DEFAULT_MISSED_BEATS)
+    }
+
+    /// Time without beats after which a host is considered offline: its beat
+    /// interval times the number of beats it may miss. Host specific
+    /// settings take precedence over the global ones.
+    pub fn offline_after(&self, interval: Option<u64>, missed_beats: Option<u64>) -> Duration {
+        Duration::from_secs(self.interval(interval).saturating_mul(self.missed_beats(missed_beats)))
+    }
+
+    /// A failure detector with the configured thresholds for a host with
+    /// the given interval.
+    pub fn detector(&self, interval: Option<u64>) -> PhiDetector {
+        PhiDetector::new(
+            self.phi_suspect.unwrap_or(DEFAULT_PHI_SUSPECT),
+            self.phi_down.unwrap_or(DEFAULT_PHI_DOWN),
+            Duration::from_secs(self.interval(interval)),
+        )
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if let Some(ref e) = self.encryption {
+            if e != keys::CHACHA20POLY1305 {
+                return Err(format!("Unsupported encryption: {}", e));
+            }
+        }
+        if let Some(ref s) = self.status {
+            if s.len() > status::MAX_STATUS_MESSAGE {
+                return Err(format!(
+                    "Status longer than {} bytes",
+                    status::MAX_STATUS_MESSAGE
+                ));
+            }
+        }
+        if let Some(ref k) = self.signing_key {
+            keys::signing_key_from_hex(k)?;
+        }
+        let servers = self.server.clone().unwrap_or_default();
+        let clients = self.client.clone().unwrap_or_default();
+        check_threshold(self.interval, self.missed_beats)?;
+        if self.renotify == Some(0) {
+            return Err("renotify must be at least 1 second".to_string());
+        }
+        if self.alert_timeout == Some(0) {
+            return Err("alert_timeout must be at least 1 second".to_string());
+        }
+        if self.alert_concurrency == Some(0) {
+            return Err("alert_concurrency must be at least 1".to_string());
+        }
+        sink::validate(self)?;
+        let suspect = self.phi_suspect.unwrap_or(DEFAULT_PHI_SUSPECT);
+        if !(suspect > 0.0 && suspect <= self.phi_down.unwrap_or(DEFAULT_PHI_DOWN)) {
+            return Err("phi_suspect must be positive and not above phi_down".to_string());
+        }
+        for s in &servers {
+            check_threshold(s.interval, s.missed_beats).map_err(
+                |e| format!("Server {}: {}", s, e),
+            )?;
+        }
+        for c in &clients {
+            check_threshold(c.interval, c.missed_beats).map_err(
+                |e| format!("Client {}: {}", c.name, e),
+            )?;
+        }
+        let shared = KeySet::new(Some(&self.key), &self.keys)?;
+        if servers.is_empty() {
+            if shared.is_empty() && clients.is_empty() {
+                return Err("No key configured".to_string());
+            }
+            if self.accept_legacy.unwrap_or(false) && self.key.len() > beat::MAX_LEGACY_KEY_SIZE {
+                return Err(format!(
+                    "Keys longer than {} bytes can't verify legacy beats, set accept_legacy = false",
+                    beat::MAX_LEGACY_KEY_SIZE
+                ));
+            }
+        }
+        for s in servers {
+            let keys = KeySet::new(Some(&s.key), &s.keys).map_err(
+                |e| format!("Server {}: {}", s, e),
+            )?;
+            if keys.is_empty() {
+                return Err(format!("Server {}: no key configured", s));
+            }
+            if let Some(ref k) = s.public_key {
+                keys::verifying_key_from_hex(k).map_err(
+                    |e| format!("Server {}: {}", s, e),
+                )?;
+            }
+        }
+        for (i, c) in clients.iter().enumerate() {
+            let keys = KeySet::new(c.key.as_ref().map(|k| k.as_str()), &c.keys)
+                .map_err(|e| format!("Client {}: {}", c.name, e))?;
+            if keys.is_empty() && c.public_key.is_none() {
+                return Err(format!("Client {}: neither key nor public_key set", c.name));
+            }
+            if let Some(ref k) = c.public_key {
+                keys::verifying_key_from_hex(k).map_err(
+                    |e| format!("Client {}: {}", c.name, e),
+                )?;
+            }
+            if clients[..i].iter().any(|o| o.name == c.name) {
+                return Err(format!("Client {} defined twice", c.name));
+            }
+        }
+        Ok(())
+    }
+
     pub fn parse_file(path: String) -> ParsingResult {
         let mut f;
         match File::open(Path::new(&path)) {
@@ -52,13 +273,34 @@ impl FlatConf {
     }
 }
 
+fn check_threshold(interval: Option<u64>, missed_beats: Option<u64>) -> Result<(), String> {
+    if interval == Some(0) {
+        return Err("interval must be at least 1 second".to_string());
+    }
+    if missed_beats == Some(0) {
+        return Err("missed_beats must be at least 1".to_string());
+    }
+    Ok(())
+}
+
+fn load_entries(entries: &mut Option<Vec<KeyEntry>>) -> Result<(), String> {
+    for e in entries.iter_mut().flat_map(|v| v.iter_mut()) {
+        if let Some(k) = keys::load_key(!e.key.is_empty(), &e.key_file, &e.key_env)
+            .map_err(|err| format!("Key {}: {}", e.id, err))?
+        {
+            e.key = k;
+        }
+    }
+    Ok(())
+}
+
 #[test]
 fn parse_test() {
     let input = "port = 1337 \n logfile = 'flat.log' \n socket = 'flat.sock' \n key = 'secret' \n \
                  verbose = true \n command = '/foo/bar/alert.sh' \n"
         .to_string();
     let input2 = "[[server]] \n address = '10.0.0.1' \n port = 8888 \n key = 'foo' \n [[server]] \
-                  \n address = '10.0.0.2' \n port = 9999 \n key = 'bar' \n";
+                  \n address = '10.0.0.2' \n port = 9999 \n key = 'bar' \n node_id = 'beta' \n";
     let mut all = input.clone() + input2;
     let conf = FlatConf::parse(&mut all).unwrap();
     let servers: Vec<Server>;
@@ -72,16 +314,18 @@ fn parse_test() {
     assert_eq!(conf.port, 1337);
     assert_eq!(conf.logfile, "flat.log");
     assert_eq!(conf.socket.unwrap(), "flat.sock");
-    assert_eq!(conf.key, "secret");
+    assert_eq!(*conf.key, "secret");
     assert_eq!(conf.verbose, true);
     assert_eq!(conf.command.unwrap(), "/foo/bar/alert.sh");
     assert_eq!(servers.len(), 2);
     assert_eq!(servers[0].address, "10.0.0.1");
     assert_eq!(servers[0].port, 8888);
-    assert_eq!(servers[0].key, "foo");
+    assert_eq!(*servers[0].key, "foo");
     assert_eq!(servers[1].address, "10.0.0.2");
     assert_eq!(servers[1].port, 9999);
-    assert_eq!(servers[1].key, "bar");
+    assert_eq!(*servers[1].key, "bar");
+    assert!(servers[0].node_id.is_none());
+    assert_eq!(servers[1].node_id, Some("beta".to_string()));
 }
 
 #[test]
@@ -98,9 +342,22 @@ fn partial_conf_parse_test() {
     assert_eq!(conf.port, 1337);
     assert_eq!(conf.logfile, "flat.log");
     assert_eq!(conf.socket.unwrap(), "flat.sock");
-    assert_eq!(conf.key, "secret");
+    assert_eq!(*conf.key, "secret");
     assert_eq!(conf.verbose, true);
     assert_eq!(conf.server.is_none(), true);
+    assert!(conf.accept_legacy.is_none());
+}
+
+#[test]
+fn accept_legacy_parse_test() {
+    let mut input = "port = 1337 \n logfile = 'flat.log' \n key = 'secret' \n verbose = false \n \
+                     accept_legacy = false \n max_skew = 5 \n"
+        .to_string();
+
+    let conf = FlatConf::parse(&mut input).unwrap();
+
+    assert_eq!(conf.accept_legacy, Some(false));
+    assert_eq!(conf.max_skew, Some(5));
 }
 
 #[test]
@@ -114,3 +371,320 @@ fn invalid_conf_parse_test() {
 
     assert!(FlatConf::parse(&mut input).is_err());
 }
+
+#[test]
+fn signing_conf_parse_test() {
+    let mut input = "port = 1337 \n logfile = 'flat.log' \n key = 'secret' \n verbose = false \n \
+                     signing_key = '0707070707070707070707070707070707070707070707070707070707070707' \n \
+                     require_signature = true \n [[client]] \n name = 'alpha' \n \
+                     public_key = 'ea4a6c63e29c520abef5507b132ec5f9954776aebebe7b92421eea691446d22c' \n"
+        .to_string();
+
+    let conf = FlatConf::parse(&mut input).unwrap();
+    let clients = conf.client.unwrap();
+
+    assert!(conf.signing_key.is_some());
+    assert_eq!(conf.require_signature, Some(true));
+    assert_eq!(clients.len(), 1);
+    assert_eq!(clients[0].name, "alpha");
+    assert!(clients[0].key.is_none());
+}
+
+#[test]
+fn client_conf_parse_test() {
+    let mut input = "port = 1337 \n logfile = 'flat.log' \n key = 'secret' \n verbose = false \n \
+                     allow_shared_key = false \n [[client]] \n name = 'alpha' \n key = 'foo' \n \
+                     [[client]] \n name = 'beta' \n key = 'bar' \n revoked = true \n"
+        .to_string();
+
+    let conf = FlatConf::parse(&mut input).unwrap();
+    let clients = conf.client.unwrap();
+
+    assert_eq!(conf.allow_shared_key, Some(false));
+    assert_eq!(clients[0].key.as_ref().map(|k| k.as_str()), Some("foo"));
+    assert!(clients[0].revoked.is_none());
+    assert_eq!(clients[1].revoked, Some(true));
+}
+
+#[test]
+fn invalid_client_conf_parse_test() {
+    let mut no_key = "port = 1337 \n logfile = 'flat.log' \n key = 'secret' \n verbose = false \n \
+                      [[client]] \n name = 'alpha' \n"
+        .to_string();
+    assert!(FlatConf::parse(&mut no_key).is_err());
+
+    let mut twice = "port = 1337 \n logfile = 'flat.log' \n key = 'secret' \n verbose = false \n \
+                     [[client]] \n name = 'alpha' \n key = 'foo' \n \
+                     [[client]] \n name = 'alpha' \n key = 'bar' \n"
+        .to_string();
+    assert!(FlatConf::parse(&mut twice).is_err());
+}
+
+#[test]
+fn invalid_signing_conf_parse_test() {
+    let mut input = "port = 1337 \n logfile = 'flat.log' \n key = 'secret' \n verbose = false \n \
+                     signing_key = 'abcd' \n"
+        .to_string();
+
+    assert!(FlatConf::parse(&mut input).is_err());
+}
+
+#[test]
+fn encryption_conf_parse_test() {
+    let mut input = "port = 1337 \n logfile = 'flat.log' \n key = 'secret' \n verbose = false \n \
+                     encryption = 'chacha20poly1305' \n"
+        .to_string();
+    let conf = FlatConf::parse(&mut input).unwrap();
+    assert_eq!(conf.encryption.unwrap(), "chacha20poly1305");
+
+    let mut invalid = "port = 1337 \n logfile = 'flat.log' \n key = 'secret' \n verbose = false \n \
+                       encryption = 'rot13' \n"
+        .to_string();
+    assert!(FlatConf::parse(&mut invalid).is_err());
+}
+
+#[test]
+fn rotating_keys_conf_parse_test() {
+    let mut input = "port = 1337 \n logfile = 'flat.log' \n key = 'secret' \n verbose = false \n \
+                     [[keys]] \n id = 'a' \n key = 'foo' \n not_after = 2018-07-01T00:00:00Z \n \
+                     [[keys]] \n id = 'b' \n key = 'bar' \n not_before = 2018-06-01 \n \
+                     [[server]] \n address = '10.0.0.1' \n port = 8888 \n key = 'foo' \n \
+                     [[server.keys]] \n id = 'c' \n key = 'baz' \n"
+        .to_string();
+
+    let conf = FlatConf::parse(&mut input).unwrap();
+    let keys = conf.keys.unwrap();
+
+    assert_eq!(keys.len(), 2);
+    assert_eq!(keys[0].id, "a");
+    assert!(keys[0].not_before.is_none());
+    assert!(keys[0].not_after.is_some());
+    assert_eq!(*conf.server.unwrap()[0].keys.clone().unwrap()[0].key, "baz");
+
+    let mut twice = "port = 1337 \n logfile = 'flat.log' \n key = 'secret' \n verbose = false \n \
+                     [[keys]] \n id = 'a' \n key = 'foo' \n [[keys]] \n id = 'a' \n key = 'bar' \n"
+        .to_string();
+    assert!(FlatConf::parse(&mut twice).is_err());
+}
+
+#[test]
+fn key_env_conf_parse_test() {
+    use std::env;
+
+    env::set_var("FLATLINED_CONF_TEST_KEY", "from-env");
+    let mut input = "port = 1337 \n logfile = 'flat.log' \n key_env = 'FLATLINED_CONF_TEST_KEY' \n \
+                     verbose = false \n [[server]] \n address = '10.0.0.1' \n port = 8888 \n \
+                     key_env = 'FLATLINED_CONF_TEST_KEY' \n"
+        .to_string();
+    let conf = FlatConf::parse(&mut input).unwrap();
+    assert_eq!(*conf.key, "from-env");
+    assert_eq!(*conf.server.unwrap()[0].key, "from-env");
+
+    let mut both = "port = 1337 \n logfile = 'flat.log' \n key = 'secret' \n \
+                    key_env = 'FLATLINED_CONF_TEST_KEY' \n verbose = false \n"
+        .to_string();
+    assert!(FlatConf::parse(&mut both).is_err());
+
+    let mut unreadable = "port = 1337 \n logfile = 'flat.log' \n key_file = '/nonexistent/flat.key' \n \
+                          verbose = false \n"
+        .to_string();
+    assert!(FlatConf::parse(&mut unreadable).is_err());
+}
+
+#[test]
+fn key_length_conf_parse_test() {
+    let long = "x".repeat(100);
+
+    let mut input = format!(
+        "port = 1337 \n logfile = 'flat.log' \n key = '{}' \n verbose = false \n",
+        long
+    );
+    assert_eq!(FlatConf::parse(&mut input).unwrap().key.len(), 100);
+
+    let mut legacy = format!(
+        "port = 1337 \n logfile = 'flat.log' \n key = '{}' \n verbose = false \n \
+         accept_legacy = true \n",
+        long
+    );
+    assert!(FlatConf::parse(&mut legacy).is_err());
+
+    let mut server = format!(
+        "port = 1337 \n logfile = 'flat.log' \n key = '' \n verbose = false \n \
+         [[server]] \n address = '10.0.0.1' \n port = 8888 \n key = '{}' \n",
+        long
+    );
+    assert!(FlatConf::parse(&mut server).is_ok());
+}
+
+#[test]
+fn empty_key_conf_parse_test() {
+    let mut input = "port = 1337 \n logfile = 'flat.log' \n key = '' \n verbose = false \n"
+        .to_string();
+    assert!(FlatConf::parse(&mut input).is_err());
+
+    let mut server = "port = 1337 \n logfile = 'flat.log' \n key = 'secret' \n verbose = false \n \
+                      [[server]] \n address = '10.0.0.1' \n port = 8888 \n key = '' \n"
+        .to_string();
+    assert!(FlatConf::parse(&mut server).is_err());
+
+    let mut client = "port = 1337 \n logfile = 'flat.log' \n key = 'secret' \n verbose = false \n \
+                      [[client]] \n name = 'alpha' \n key = '' \n"
+        .to_string();
+    assert!(FlatConf::parse(&mut client).is_err());
+
+    let mut rotating = "port = 1337 \n logfile = 'flat.log' \n key = '' \n verbose = false \n \
+                        [[keys]] \n id = 'a' \n key = 'foo' \n"
+        .to_string();
+    assert!(FlatConf::parse(&mut rotating).is_ok());
+}
+
+#[test]
+fn status_conf_parse_test() {
+    let mut input = "port = 1337 \n logfile = 'flat.log' \n key = 'secret' \n verbose = false \n \
+                     send_status = true \n status = 'maintenance' \n status_path = '/var' \n"
+        .to_string();
+    let conf = FlatConf::parse(&mut input).unwrap();
+    assert_eq!(conf.send_status, Some(true));
+    assert_eq!(conf.status.unwrap(), "maintenance");
+    assert_eq!(conf.status_path.unwrap(), "/var");
+
+    let mut long = format!(
+        "port = 1337 \n logfile = 'flat.log' \n key = 'secret' \n verbose = false \n \
+         status = '{}' \n",
+        "x".repeat(status::MAX_STATUS_MESSAGE + 1)
+    );
+    assert!(FlatConf::parse(&mut long).is_err());
+}
+
+#[test]
+fn ack_conf_parse_test() {
+    let mut input = "port = 1337 \n logfile = 'flat.log' \n key = 'secret' \n verbose = false \n \
+                     ack = true \n ack_timeout = 3 \n [[server]] \n address = '10.0.0.1' \n \
+                     port = 8888 \n key = 'foo' \n \
+                     public_key = 'ea4a6c63e29c520abef5507b132ec5f9954776aebebe7b92421eea691446d22c' \n"
+        .to_string();
+    let conf = FlatConf::parse(&mut input).unwrap();
+    assert_eq!(conf.ack, Some(true));
+    assert_eq!(conf.ack_timeout, Some(3));
+    assert!(conf.server.unwrap()[0].public_key.is_some());
+
+    let mut invalid = "port = 1337 \n logfile = 'flat.log' \n key = 'secret' \n verbose = false \n \
+                       [[server]] \n address = '10.0.0.1' \n port = 8888 \n key = 'foo' \n \
+                       public_key = 'abcd' \n"
+        .to_string();
+    assert!(FlatConf::parse(&mut invalid).is_err());
+}
+
+#[test]
+fn threshold_conf_parse_test() {
+    let mut input = "port = 1337 \n logfile = 'flat.log' \n key = 'secret' \n verbose = false \n \
+                     interval = 5 \n missed_beats = 3 \n [[server]] \n address = '10.0.0.1' \n \
+                     port = 8888 \n key = 'foo' \n interval = 30 \n [[server]] \n \
+                     address = '10.0.0.2' \n port = 8888 \n key = 'foo' \n missed_beats = 10 \n"
+        .to_string();
+    let conf = FlatConf::parse(&mut input).unwrap();
+    let servers = conf.server.clone().unwrap();
+
+    assert_eq!(conf.offline_after(None, None), Duration::from_secs(15));
+    assert_eq!(
+        conf.offline_after(servers[0].interval, servers[0].missed_beats),
+        Duration::from_secs(90)
+    );
+    assert_eq!(
+        conf.offline_after(servers[1].interval, servers[1].missed_beats),
+        Duration::from_secs(50)
+    );
+    assert_eq!(conf.interval(servers[0].interval), 30);
+    assert_eq!(conf.missed_beats(None), 3);
+    assert_eq!(conf.missed_beats(servers[1].missed_beats), 10);
+
+    let mut defaults = "port = 1337 \n logfile = 'flat.log' \n key = 'secret' \n verbose = false \n"
+        .to_string();
+    let conf = FlatConf::parse(&mut defaults).unwrap();
+    assert_eq!(conf.offline_after(None, None), Duration::from_secs(60));
+
+    let mut zero = "port = 1337 \n logfile = 'flat.log' \n key = 'secret' \n verbose = false \n \
+                    [[client]] \n name = 'alpha' \n key = 'foo' \n interval = 0 \n"
+        .to_string();
+    assert!(FlatConf::parse(&mut zero).is_err());
+}
+
+#[test]
+fn phi_conf_parse_test() {
+    let mut input = "port = 1337 \n logfile = 'flat.log' \n key = 'secret' \n verbose = false \n \
+                     phi_suspect = 3.5 \n phi_down = 10.0 \n"
+        .to_string();
+    let conf = FlatConf::parse(&mut input).unwrap();
+    assert_eq!(conf.phi_suspect, Some(3.5));
+    assert_eq!(conf.phi_down, Some(10.0));
+
+    let mut reversed = "port = 1337 \n logfile = 'flat.log' \n key = 'secret' \n verbose = false \n \
+                        phi_suspect = 12.0 \n"
+        .to_string();
+    assert!(FlatConf::parse(&mut reversed).is_err());
+}
+
+#[test]
+fn alert_conf_parse_test() {
+    let mut input = "port = 1337 \n logfile = 'flat.log' \n key = 'secret' \n verbose = false \n \
+                     command = 'down.sh' \n recovery_command = 'up.sh' \n renotify = 3600 \n"
+        .to_string();
+    let conf = FlatConf::parse(&mut input).unwrap();
+    assert_eq!(conf.recovery_command, Some("up.sh".to_string()));
+    assert_eq!(conf.renotify, Some(3600));
+    assert_eq!(conf.alert_timeout, None);
+
+    let mut limits = "port = 1337 \n logfile = 'flat.log' \n key = 'secret' \n verbose = false \n \
+                      alert_timeout = 10 \n alert_concurrency = 2 \n alert_retries = 0 \n"
+        .to_string();
+    let conf = FlatConf::parse(&mut limits).unwrap();
+    assert_eq!(conf.alert_timeout, Some(10));
+    assert_eq!(conf.alert_concurrency, Some(2));
+    assert_eq!(conf.alert_retries, Some(0));
+
+    let mut zero = "port = 1337 \n logfile = 'flat.log' \n key = 'secret' \n verbose = false \n \
+                    renotify = 0 \n"
+        .to_string();
+    assert!(FlatConf::parse(&mut zero).is_err());
+
+    let mut no_slots = "port = 1337 \n logfile = 'flat.log' \n key = 'secret' \n verbose = false \n \
+                        alert_concurrency = 0 \n"
+        .to_string();
+    assert!(FlatConf::parse(&mut no_slots).is_err());
+}
+
+#[test]
+fn webhook_conf_parse_test() {
+    let mut input = "port = 1337 \n logfile = 'flat.log' \n key = 'secret' \n verbose = false \n \
+                     webhook = 'https://chat.example.com/hooks/flat' \n"
+        .to_string();
+    let conf = FlatConf::parse(&mut input).unwrap();
+    assert_eq!(conf.webhook, Some("https://chat.example.com/hooks/flat".to_string()));
+
+    let mut ftp = "port = 1337 \n logfile = 'flat.log' \n key = 'secret' \n verbose = false \n \
+                   webhook = 'ftp://example.com/' \n"
+        .to_string();
+    assert!(FlatConf::parse(&mut ftp).is_err());
+}
+
+#[test]
+fn smtp_conf_parse_test() {
+    use std::env;
+
+    let mut input = "port = 1337 \n logfile = 'flat.log' \n key = 'secret' \n verbose = false \n \
+                     [smtp] \n relay = 'mail.example.com' \n username = 'flat' \n \
+                     password_env = 'FLAT_TEST_SMTP_PASSWORD' \n from = 'flat@example.com' \n \
+                     to = ['ops@example.com'] \n"
+        .to_string();
+    env::set_var("FLAT_TEST_SMTP_PASSWORD", "hunter2");
+    let conf = FlatConf::parse(&mut input).unwrap();
+    let smtp = conf.smtp.unwrap();
+    assert_eq!(smtp.relay, "mail.example.com");
+    assert_eq!(smtp.password.unwrap().as_str(), "hunter2");
+    assert_eq!(smtp.starttls, None);
+
+    let mut no_rcpt = "port = 1337 \n logfile = 'flat.log' \n key = 'secret' \n verbose = false \n \
+                       [smtp] \n relay = 'mail.example.com' \n from = 'flat@example.com' \n to = [] \n"
+        .to_string();
+    assert!(FlatConf::parse(&mut no_rcpt).is_err());
+}
@@ -0,0 +1,323 @@
+use beat::{Beat, BeatError};
+use client::Client;
+use ed25519_dalek::VerifyingKey;
+use flatconf::FlatConf;
+use keys;
+use keys::{KeySet, Secret};
+use std::time::*;
+
+/// A shared key as `(key ID, key)`.
+type SharedKey<'a> = (Option<&'a str>, &'a str);
+
+struct KnownClient {
+    name: String,
+    keys: KeySet,
+    public_key: Option<VerifyingKey>,
+    revoked: bool,
+}
+
+impl KnownClient {
+    fn new(c: &Client) -> Result<KnownClient, String> {
+        let public_key = match c.public_key {
+            Some(ref k) => Some(keys::verifying_key_from_hex(k)?),
+            None => None,
+        };
+        Ok(KnownClient {
+            name: c.name.clone(),
+            keys: KeySet::new(c.key.as_ref().map(|k| k.as_str()), &c.keys)?,
+            public_key,
+            revoked: c.revoked.unwrap_or(false),
+        })
+    }
+}
+
+/// Who sent a verified beat.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identity {
+    /// Name of the known client, `None` for beats with the shared key.
+    pub client: Option<String>,
+    /// ID of the shared key the beat was authenticated or encrypted with.
+    pub key_id: Option<String>,
+    /// The shared key itself, acknowledgements are authenticated with it.
+    pub key: Option<Secret>,
+}
+
+/// Keys a listening server verifies incoming beats with.
+///
+/// A beat whose node ID names a known client has to be authenticated with
+/// that client's own key or signature. Beats of unknown senders are checked
+/// against the shared key, unless that is disabled. Beats carrying a key ID
+/// are only checked against the currently valid key with that ID.
+pub struct Keyring {
+    keys: KeySet,
+    clients: Vec<KnownClient>,
+    require_signature: bool,
+    require_encryption: bool,
+    allow_shared_key: bool,
+}
+
+impl Keyring {
+    pub fn new(conf: &FlatConf) -> Result<Keyring, String> {
+        let mut clients = Vec::new();
+        for c in conf.client.clone().unwrap_or_default() {
+            clients.push(KnownClient::new(&c)?);
+        }
+        Ok(Keyring {
+            keys: KeySet::new(Some(&conf.key), &conf.keys)?,
+            clients,
+            require_signature: conf.require_signature.unwrap_or(false),
+            require_encryption: conf.encryption.is_some(),
+            allow_shared_key: conf.allow_shared_key.unwrap_or(true),
+        })
+    }
+
+    /// Verifies and decrypts a beat and returns who sent it.
+    pub fn authenticate(&self, beat: &mut Beat) -> Result<Identity, BeatError> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let (client, mut key) = self.verify(beat, now)?;
+        if beat.is_sealed() {
+            let candidates = match key {
+                Some(k) => vec![k],
+                None => self.keys_of(client).accepted(beat.key_id().as_deref(), now),
+            };
+            key = candidates.into_iter().find(|&(_, k)| {
+                beat.open(&keys::encryption_key(k)).is_ok()
+            });
+            if key.is_none() {
+                return Err(BeatError::DecryptionFailed);
+            }
+        } else if self.require_encryption && !beat.is_legacy() {
+            return Err(BeatError::NotEncrypted);
+        }
+        Ok(Identity {
+            client: client.map(|c| c.name.clone()),
+            key_id: key.and_then(|(id, _)| id.map(|i| i.to_string())),
+            key: key.map(|(_, k)| Secret::new(k.to_string())),
+        })
+    }
+
+    /// Keys a client's beats are encrypted with. Clients that only sign
+    /// their beats use the shared keys.
+    fn keys_of<'a>(&'a self, client: Option<&'a KnownClient>) -> &'a KeySet {
+        match client {
+            Some(c) if !c.keys.is_empty() => &c.keys,
+            _ => &self.keys,
+        }
+    }
+
+    fn verify(
+        &self,
+        beat: &Beat,
+        now: u64,
+    ) -> Result<(Option<&KnownClient>, Option<SharedKey<'_>>), BeatError> {
+        let claimed = beat.node_id().and_then(
+            |n| self.clients.iter().find(|c| c.name == n),
+        );
+        if let Some(c) = claimed {
+            return self.verify_client(c, beat, now).map(|k| (Some(c), k));
+        }
+
+        if beat.is_signed() {
+            match self.clients.iter().find(|c| {
+                !c.revoked && self.verify_client(c, beat, now).is_ok()
+            }) {
+                Some(c) => Ok((Some(c), None)),
+                None => Err(BeatError::WrongChecksum),
+            }
+        } else if self.require_signature || !self.allow_shared_key {
+            Err(BeatError::WrongChecksum)
+        } else {
+            Keyring::verify_keys(&self.keys, beat, now).map(|k| (None, Some(k)))
+        }
+    }
+
+    /// Returns the shared key the beat was authenticated with, `None` for
+    /// signed beats.
+    fn verify_client<'a>(
+        &self,
+        client: &'a KnownClient,
+        beat: &Beat,
+        now: u64,
+    ) -> Result<Option<SharedKey<'a>>, BeatError> {
+        if client.revoked {
+            return Err(BeatError::Revoked);
+        }
+        if beat.is_signed() {
+            match client.public_key {
+                Some(ref k) => beat.verify_signature(k).map(|_| None),
+                None => Err(BeatError::WrongChecksum),
+            }
+        } else if self.require_signature {
+            Err(BeatError::WrongChecksum)
+        } else {
+            Keyring::verify_keys(&client.keys, beat, now).map(Some)
+        }
+    }
+
+    fn verify_keys<'a>(keys: &'a KeySet, beat: &Beat, now: u64) -> Result<SharedKey<'a>, BeatError> {
+        match keys.accepted(beat.key_id().as_deref(), now).into_iter().find(
+            |&(_, k)| beat.verify_beat(k).is_ok(),
+        ) {
+            Some(k) => Ok(k),
+            None => Err(BeatError::WrongChecksum),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use beat::Extension;
+    use ed25519_dalek::SigningKey;
+    use hex;
+    use testutil::conf;
+
+    impl Keyring {
+        fn authenticate_client(&self, beat: &mut Beat) -> Result<Option<String>, BeatError> {
+            self.authenticate(beat).map(|i| i.client)
+        }
+    }
+
+    fn keyring(options: &str) -> Keyring {
+        Keyring::new(&conf(&format!(
+            "{} \n \
+             [[keys]] \n id = 'next' \n key = 'next-key' \n not_before = 2018-06-01 \n \
+             [[keys]] \n id = 'expired' \n key = 'expired-key' \n not_after = 2018-06-01 \n \
+             [[client]] \n name = 'alpha' \n public_key = '{}' \n \
+             [[client]] \n name = 'beta' \n public_key = '{}' \n \
+             [[client]] \n name = 'gamma' \n key = 'gamma-key' \n \
+             [[client]] \n name = 'delta' \n key = 'delta-key' \n revoked = true \n \
+             [[client]] \n name = 'epsilon' \n [[client.keys]] \n id = 'e1' \n key = 'e1-key' \n",
+            options,
+            hex::encode(SigningKey::from_bytes(&[1u8; 32]).verifying_key().to_bytes()),
+            hex::encode(SigningKey::from_bytes(&[2u8; 32]).verifying_key().to_bytes())
+        ))).unwrap()
+    }
+
+    fn key_id(k: &Keyring, beat: &mut Beat) -> Option<String> {
+        k.authenticate(beat).unwrap().key_id
+    }
+
+    fn signed(seed: u8, node_id: Option<&str>) -> Beat {
+        let ext = node_id.map(|n| vec![Extension::node_id(n)]).unwrap_or_default();
+        Beat::with_signature(&SigningKey::from_bytes(&[seed; 32]), ext)
+    }
+
+    fn keyed(key: &str, node_id: &str) -> Beat {
+        Beat::with_extensions(key, vec![Extension::node_id(node_id)])
+    }
+
+    #[test]
+    fn identify_client_test() {
+        let k = keyring("");
+
+        assert_eq!(
+            k.authenticate_client(&mut signed(1, None)).unwrap(),
+            Some("alpha".to_string())
+        );
+        assert_eq!(
+            k.authenticate_client(&mut signed(2, Some("beta"))).unwrap(),
+            Some("beta".to_string())
+        );
+        assert!(k.authenticate_client(&mut signed(3, None)).is_err());
+        // alpha's key claiming to be beta
+        assert!(k.authenticate_client(&mut signed(1, Some("beta"))).is_err());
+    }
+
+    #[test]
+    fn client_key_test() {
+        let k = keyring("");
+
+        assert_eq!(
+            k.authenticate_client(&mut keyed("gamma-key", "gamma")).unwrap(),
+            Some("gamma".to_string())
+        );
+        // the shared key does not work for a known client
+        assert!(k.authenticate_client(&mut keyed("secret", "gamma")).is_err());
+        assert!(k.authenticate_client(&mut keyed("delta-key", "gamma")).is_err());
+    }
+
+    #[test]
+    fn revoked_client_test() {
+        let k = keyring("");
+
+        assert!(k.authenticate_client(&mut keyed("delta-key", "delta")).is_err());
+        assert!(k.authenticate_client(&mut keyed("secret", "delta")).is_err());
+    }
+
+    #[test]
+    fn shared_key_test() {
+        assert_eq!(
+            keyring("").authenticate_client(&mut Beat::new("secret")).unwrap(),
+            None
+        );
+        assert_eq!(
+            keyring("")
+                .authenticate_client(&mut keyed("secret", "unknown"))
+                .unwrap(),
+            None
+        );
+        assert!(keyring("").authenticate_client(&mut Beat::new("wrong")).is_err());
+        assert!(
+            keyring("require_signature = true")
+                .authenticate_client(&mut Beat::new("secret"))
+                .is_err()
+        );
+        assert!(
+            keyring("allow_shared_key = false")
+                .authenticate_client(&mut Beat::new("secret"))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn rotated_key_test() {
+        let k = keyring("");
+        let mut next = Beat::with_extensions("next-key", vec![Extension::key_id("next")]);
+        let mut expired = Beat::with_extensions("expired-key", vec![Extension::key_id("expired")]);
+        let mut wrong_id = Beat::with_extensions("next-key", vec![Extension::key_id("other")]);
+        let mut no_id = Beat::with_extensions("next-key", Vec::new());
+        let mut client = Beat::with_extensions(
+            "e1-key",
+            vec![Extension::node_id("epsilon"), Extension::key_id("e1")],
+        );
+
+        assert_eq!(key_id(&k, &mut next), Some("next".to_string()));
+        assert!(k.authenticate(&mut expired).is_err());
+        assert!(k.authenticate(&mut wrong_id).is_err());
+        assert_eq!(key_id(&k, &mut no_id), Some("next".to_string()));
+        assert_eq!(key_id(&k, &mut Beat::new("secret")), None);
+        assert_eq!(key_id(&k, &mut client), Some("e1".to_string()));
+    }
+
+    #[test]
+    fn encrypted_test() {
+        let k = keyring("encryption = 'chacha20poly1305'");
+        let mut sealed = Beat::unsigned(Vec::new());
+        sealed.seal(
+            &keys::encryption_key("secret"),
+            vec![Extension::sequence(1, 2)],
+        );
+        sealed.authenticate("secret");
+
+        assert!(k.authenticate_client(&mut sealed).is_ok());
+        assert_eq!(sealed.sequence(), Some((1, 2)));
+        assert!(k.authenticate_client(&mut Beat::new("secret")).is_err());
+    }
+
+    #[test]
+    fn encrypted_client_key_test() {
+        let mut sealed = Beat::unsigned(vec![Extension::node_id("gamma")]);
+        sealed.seal(
+            &keys::encryption_key("gamma-key"),
+            vec![Extension::sequence(1, 2)],
+        );
+        sealed.authenticate("gamma-key");
+
+        assert!(keyring("").authenticate_client(&mut sealed).is_ok());
+        assert_eq!(sealed.sequence(), Some((1, 2)));
+    }
+}
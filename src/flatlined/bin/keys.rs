@@ -0,0 +1,487 @@
+extern crate toml;
+
+use blake2_rfc::blake2b::Blake2b;
+use ed25519_dalek::{SigningKey, VerifyingKey, PUBLIC_KEY_LENGTH, SECRET_KEY_LENGTH};
+use hex;
+use rand::{OsRng, Rng};
+use self::toml::value::Datetime;
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io::prelude::*;
+use std::ops::Deref;
+use std::os::unix::fs::PermissionsExt;
+use zeroize::Zeroizing;
+
+/// Key material that is overwritten with zeros when dropped and left out
+/// of `Debug` output.
+#[derive(Deserialize, Clone, Default, PartialEq)]
+#[serde(transparent)]
+pub struct Secret(Zeroizing<String>);
+
+impl Secret {
+    pub fn new(key: String) -> Secret {
+        Secret(Zeroizing::new(key))
+    }
+}
+
+impl Deref for Secret {
+    type Target = String;
+
+    fn deref(&self) -> &String {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str("Secret(***)")
+    }
+}
+
+/// One of several keys that are valid during a period of time, so keys can
+/// be rotated without a window where senders and receivers disagree.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct KeyEntry {
+    pub id: String,
+    #[serde(default)]
+    pub key: Secret,
+    pub key_file: Option<String>,
+    pub key_env: Option<String>,
+    pub not_before: Option<Datetime>,
+    pub not_after: Option<Datetime>,
+}
+
+struct ValidKey {
+    id: String,
+    key: Secret,
+    not_before: u64,
+    not_after: u64,
+}
+
+/// A key without ID plus any number of keys with validity periods.
+pub struct KeySet {
+    default: Option<Secret>,
+    keys: Vec<ValidKey>,
+}
+
+impl KeySet {
+    pub fn new(default: Option<&str>, entries: &Option<Vec<KeyEntry>>) -> Result<KeySet, String> {
+        let mut keys: Vec<ValidKey> = Vec::new();
+        for e in entries.clone().unwrap_or_default() {
+            if keys.iter().any(|k| k.id == e.id) {
+                return Err(format!("Key {} defined twice", e.id));
+            }
+            if e.key.is_empty() {
+                return Err(format!("Key {} is empty", e.id));
+            }
+            let not_before = match e.not_before {
+                Some(ref d) => timestamp_from_datetime(d)?,
+                None => 0,
+            };
+            let not_after = match e.not_after {
+                Some(ref d) => timestamp_from_datetime(d)?,
+                None => u64::MAX,
+            };
+            if not_before > not_after {
+                return Err(format!("Key {} expires before it becomes valid", e.id));
+            }
+            keys.push(ValidKey {
+                id: e.id,
+                key: e.key,
+                not_before,
+                not_after,
+            });
+        }
+        Ok(KeySet {
+            default: default.filter(|k| !k.is_empty()).map(|k| {
+                Secret::new(k.to_string())
+            }),
+            keys,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.default.is_none() && self.keys.is_empty()
+    }
+
+    fn valid(&self, now: u64) -> Vec<&ValidKey> {
+        self.keys
+            .iter()
+            .filter(|k| k.not_before <= now && now <= k.not_after)
+            .collect()
+    }
+
+    /// The key a sender uses: the valid key that became valid last, the key
+    /// without ID if none is valid.
+    pub fn current(&self, now: u64) -> Option<(Option<&str>, &str)> {
+        match self.valid(now).into_iter().max_by_key(|k| k.not_before) {
+            Some(k) => Some((Some(&k.id), &k.key)),
+            None => self.default.as_ref().map(|d| (None, d.as_str())),
+        }
+    }
+
+    /// The keys a receiver accepts for a beat with the given key ID. Beats
+    /// without key ID are tried with the key without ID first.
+    pub fn accepted(&self, id: Option<&str>, now: u64) -> Vec<(Option<&str>, &str)> {
+        let valid = self.valid(now).into_iter().map(|k| (Some(k.id.as_str()), k.key.as_str()));
+        match id {
+            Some(id) => valid.filter(|&(i, _)| i == Some(id)).collect(),
+            None => {
+                self.default
+                    .iter()
+                    .map(|d| (None, d.as_str()))
+                    .chain(valid)
+                    .collect()
+            }
+        }
+    }
+}
+
+fn days_from_civil(year: i64, month: u64, day: u64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Converts a TOML date or date-time into seconds since the epoch. Values
+/// without offset are taken as UTC.
+pub fn timestamp_from_datetime(datetime: &Datetime) -> Result<u64, String> {
+    let input = datetime.to_string();
+    let invalid = || format!("Invalid date: {}", input);
+    let num = |s: &str| s.parse::<u64>().map_err(|_| invalid());
+
+    if input.len() < 10 || !input.is_char_boundary(10) {
+        return Err(invalid());
+    }
+    let (date, rest) = input.split_at(10);
+    let parts: Vec<&str> = date.split('-').collect();
+    if parts.len() != 3 {
+        return Err(invalid());
+    }
+    let days = days_from_civil(num(parts[0])? as i64, num(parts[1])?, num(parts[2])?);
+
+    let mut seconds = 0i64;
+    if !rest.is_empty() {
+        let rest = &rest[1..];
+        if rest.len() < 8 || !rest.is_char_boundary(8) {
+            return Err(invalid());
+        }
+        let (time, mut offset) = rest.split_at(8);
+        let t: Vec<&str> = time.split(':').collect();
+        if t.len() != 3 {
+            return Err(invalid());
+        }
+        seconds = (num(t[0])? * 3600 + num(t[1])? * 60 + num(t[2])?) as i64;
+
+        if offset.starts_with('.') {
+            offset = offset.trim_start_matches(|c: char| c == '.' || c.is_ascii_digit());
+        }
+        if offset.len() == 6 {
+            let o = (num(&offset[1..3])? * 3600 + num(&offset[4..6])? * 60) as i64;
+            match &offset[..1] {
+                "+" => seconds -= o,
+                "-" => seconds += o,
+                _ => return Err(invalid()),
+            }
+        } else if !(offset.is_empty() || offset == "Z") {
+            return Err(invalid());
+        }
+    }
+
+    let ret = days * 86_400 + seconds;
+    if ret < 0 { Err(invalid()) } else { Ok(ret as u64) }
+}
+
+/// Reads a key from a file that must not be accessible by other users.
+pub fn read_key_file(path: &str) -> Result<Secret, String> {
+    let mut f = File::open(path).map_err(|e| format!("Key file {}: {}", path, e))?;
+    let mode = f.metadata()
+        .map_err(|e| format!("Key file {}: {}", path, e))?
+        .permissions()
+        .mode();
+    if mode & 0o007 != 0 {
+        return Err(format!(
+            "Key file {} is accessible by other users (mode {:o})",
+            path,
+            mode & 0o777
+        ));
+    }
+
+    let mut buffer = Zeroizing::new(String::new());
+    f.read_to_string(&mut buffer).map_err(
+        |e| format!("Key file {}: {}", path, e),
+    )?;
+    Ok(Secret::new(buffer.trim_end_matches(&['\r', '\n'][..]).to_string()))
+}
+
+/// Resolves a key that may be given inline, in a file or in an environment
+/// variable. Returns `None` if neither file nor variable are set, so the
+/// inline key is used unchanged.
+pub fn load_key(
+    inline: bool,
+    file: &Option<String>,
+    var: &Option<String>,
+) -> Result<Option<Secret>, String> {
+    match (file.as_ref(), var.as_ref()) {
+        (Some(_), Some(_)) => Err("Only one of key_file and key_env may be set".to_string()),
+        (Some(_), _) | (_, Some(_)) if inline => {
+            Err("An inline key can't be combined with key_file or key_env".to_string())
+        }
+        (Some(f), None) => read_key_file(f).map(Some),
+        (None, Some(v)) => {
+            match env::var(v) {
+                Ok(k) => Ok(Some(Secret::new(k))),
+                Err(_) => Err(format!("Environment variable {} not set", v)),
+            }
+        }
+        (None, None) => Ok(None),
+    }
+}
+
+/// Name of the only supported beat encryption.
+pub static CHACHA20POLY1305: &'static str = "chacha20poly1305";
+
+/// Derives a key for a single purpose from a shared key of any length.
+fn derive_key(context: &[u8], key: &str, out: &mut [u8]) {
+    let mut ctx = Blake2b::new(out.len());
+    ctx.update(context);
+    ctx.update(key.as_bytes());
+    out.clone_from_slice(ctx.finalize().as_bytes());
+}
+
+/// Derives the key beats are authenticated with from the shared key.
+pub fn mac_key(key: &str) -> Zeroizing<[u8; 64]> {
+    let mut ret = Zeroizing::new([0u8; 64]);
+    derive_key(b"flatlined mac key", key, &mut *ret);
+    ret
+}
+
+/// Derives the key beat extensions are encrypted with from the shared key.
+pub fn encryption_key(key: &str) -> Zeroizing<[u8; 32]> {
+    let mut ret = Zeroizing::new([0u8; 32]);
+    derive_key(b"flatlined encryption key", key, &mut *ret);
+    ret
+}
+
+/// Parses a hex encoded Ed25519 secret key.
+pub fn signing_key_from_hex(input: &str) -> Result<SigningKey, String> {
+    let bytes = Zeroizing::new(hex::decode(input.trim()).map_err(
+        |e| format!("Invalid signing key: {}", e),
+    )?);
+    if bytes.len() != SECRET_KEY_LENGTH {
+        return Err(format!(
+            "Invalid signing key: expected {} bytes, got {}",
+            SECRET_KEY_LENGTH,
+            bytes.len()
+        ));
+    }
+    let mut secret = Zeroizing::new([0u8; SECRET_KEY_LENGTH]);
+    secret.clone_from_slice(&bytes);
+    Ok(SigningKey::from_bytes(&secret))
+}
+
+/// Parses a hex encoded Ed25519 public key.
+pub fn verifying_key_from_hex(input: &str) -> Result<VerifyingKey, String> {
+    let bytes = hex::decode(input.trim()).map_err(|e| format!("Invalid public key: {}", e))?;
+    if bytes.len() != PUBLIC_KEY_LENGTH {
+        return Err(format!(
+            "Invalid public key: expected {} bytes, got {}",
+            PUBLIC_KEY_LENGTH,
+            bytes.len()
+        ));
+    }
+    let mut public = [0u8; PUBLIC_KEY_LENGTH];
+    public.clone_from_slice(&bytes);
+    VerifyingKey::from_bytes(&public).map_err(|e| format!("Invalid public key: {}", e))
+}
+
+/// Generates a new key pair and returns the hex encoded secret and public key.
+pub fn generate_signing_key() -> Result<(String, String), String> {
+    let mut rng = OsRng::new().map_err(|e| e.to_string())?;
+    let mut secret = Zeroizing::new([0u8; SECRET_KEY_LENGTH]);
+    rng.fill_bytes(&mut *secret);
+    let key = SigningKey::from_bytes(&secret);
+    Ok((
+        hex::encode(key.to_bytes()),
+        hex::encode(key.verifying_key().to_bytes()),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::process;
+
+    #[test]
+    fn generated_key_test() {
+        let (secret, public) = generate_signing_key().unwrap();
+        let key = signing_key_from_hex(&secret).unwrap();
+
+        assert_eq!(key.verifying_key(), verifying_key_from_hex(&public).unwrap());
+    }
+
+    #[test]
+    fn encryption_key_test() {
+        assert_eq!(*encryption_key("foo"), *encryption_key("foo"));
+        assert!(*encryption_key("foo") != *encryption_key("bar"));
+        assert!(*encryption_key("foo") != [0u8; 32]);
+    }
+
+    #[test]
+    fn mac_key_test() {
+        let long = "x".repeat(200);
+
+        assert_eq!(*mac_key(&long), *mac_key(&long));
+        assert!(*mac_key(&long) != *mac_key(&"x".repeat(201)));
+        assert!(mac_key("foo")[..32] != encryption_key("foo")[..]);
+    }
+
+    fn datetime(input: &str) -> Datetime {
+        input.parse().unwrap()
+    }
+
+    #[test]
+    fn datetime_test() {
+        assert_eq!(timestamp_from_datetime(&datetime("1970-01-01")).unwrap(), 0);
+        assert_eq!(
+            timestamp_from_datetime(&datetime("2018-06-01")).unwrap(),
+            1_527_811_200
+        );
+        assert_eq!(
+            timestamp_from_datetime(&datetime("2018-06-01T12:30:15Z")).unwrap(),
+            1_527_856_215
+        );
+        assert_eq!(
+            timestamp_from_datetime(&datetime("2018-06-01T14:30:15.123+02:00")).unwrap(),
+            1_527_856_215
+        );
+        assert_eq!(
+            timestamp_from_datetime(&datetime("2018-06-01T12:30:15")).unwrap(),
+            1_527_856_215
+        );
+        assert!(timestamp_from_datetime(&datetime("1969-12-31")).is_err());
+    }
+
+    fn entry(id: &str, key: &str, not_before: Option<&str>, not_after: Option<&str>) -> KeyEntry {
+        KeyEntry {
+            id: id.to_string(),
+            key: Secret::new(key.to_string()),
+            key_file: None,
+            key_env: None,
+            not_before: not_before.map(datetime),
+            not_after: not_after.map(datetime),
+        }
+    }
+
+    fn key_set() -> KeySet {
+        let entries = vec![
+            entry("old", "old-key", None, Some("2018-07-01")),
+            entry("new", "new-key", Some("2018-06-01"), None),
+        ];
+        KeySet::new(Some("default"), &Some(entries)).unwrap()
+    }
+
+    #[test]
+    fn current_key_test() {
+        let keys = key_set();
+        let may = 1_525_132_800;
+        let june = 1_528_000_000;
+        let july = 1_531_000_000;
+
+        assert_eq!(keys.current(may), Some((Some("old"), "old-key")));
+        assert_eq!(keys.current(june), Some((Some("new"), "new-key")));
+        assert_eq!(keys.current(july), Some((Some("new"), "new-key")));
+        assert_eq!(
+            KeySet::new(Some("default"), &None).unwrap().current(may),
+            Some((None, "default"))
+        );
+        assert!(KeySet::new(None, &None).unwrap().current(may).is_none());
+    }
+
+    #[test]
+    fn accepted_key_test() {
+        let keys = key_set();
+        let june = 1_528_000_000;
+        let july = 1_531_000_000;
+
+        assert_eq!(keys.accepted(Some("old"), june), vec![(Some("old"), "old-key")]);
+        assert!(keys.accepted(Some("old"), july).is_empty());
+        assert!(keys.accepted(Some("unknown"), june).is_empty());
+        assert_eq!(
+            keys.accepted(None, june),
+            vec![
+                (None, "default"),
+                (Some("old"), "old-key"),
+                (Some("new"), "new-key"),
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_key_set_test() {
+        let twice = vec![entry("a", "a", None, None), entry("a", "b", None, None)];
+        assert!(KeySet::new(None, &Some(twice)).is_err());
+
+        let empty = vec![entry("a", "", None, None)];
+        assert!(KeySet::new(None, &Some(empty)).is_err());
+        assert!(KeySet::new(Some(""), &None).unwrap().is_empty());
+
+        let reversed = vec![entry("a", "a", Some("2018-07-01"), Some("2018-06-01"))];
+        assert!(KeySet::new(None, &Some(reversed)).is_err());
+    }
+
+    fn key_file(name: &str, mode: u32, content: &str) -> String {
+        let path = env::temp_dir().join(format!("flatlined-{}-{}", name, process::id()));
+        let mut f = File::create(&path).unwrap();
+        f.write_all(content.as_bytes()).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn key_file_test() {
+        let path = key_file("key", 0o600, "secret\n");
+        assert_eq!(*read_key_file(&path).unwrap(), "secret");
+        assert_eq!(
+            *load_key(false, &Some(path.clone()), &None).unwrap().unwrap(),
+            "secret"
+        );
+        fs::remove_file(path).unwrap();
+
+        assert!(read_key_file("/nonexistent/flatlined.key").is_err());
+    }
+
+    #[test]
+    fn world_readable_key_file_test() {
+        let path = key_file("readable", 0o644, "secret");
+        assert!(read_key_file(&path).is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn key_env_test() {
+        env::set_var("FLATLINED_TEST_KEY", "env-secret");
+        let var = Some("FLATLINED_TEST_KEY".to_string());
+
+        assert_eq!(*load_key(false, &None, &var).unwrap().unwrap(), "env-secret");
+        assert!(load_key(true, &None, &var).is_err());
+        assert!(load_key(false, &Some("/tmp/key".to_string()), &var).is_err());
+        assert!(
+            load_key(false, &None, &Some("FLATLINED_UNSET_KEY".to_string())).is_err()
+        );
+        assert!(load_key(true, &None, &None).unwrap().is_none());
+    }
+
+    #[test]
+    fn invalid_key_test() {
+        assert!(signing_key_from_hex("abcd").is_err());
+        assert!(signing_key_from_hex("not hex").is_err());
+        assert!(verifying_key_from_hex(&"00".repeat(31)).is_err());
+    }
+}
@@ -0,0 +1,352 @@
+use alert::{Alert, Digest, Notification};
+use executor::{DEFAULT_ALERT_RETRIES, DEFAULT_ALERT_TIMEOUT};
+use flatconf::FlatConf;
+use keys::Secret;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+use queue::{Queue, RETRY_DELAY};
+use sink::AlertSink;
+use std::fmt;
+use std::time::Duration;
+
+static DEFAULT_SUBJECT: &'static str = "flatlined: {host} is {state}";
+static DEFAULT_BODY: &'static str = "Host {host} ({address}) is {state}, it was {previous_state}.\n\
+                                     Last beat: {last_seen}\n\
+                                     Downtime: {downtime}s\n\
+                                     Rx: {rx} Tx: {tx} Rejected: {rejected}\n";
+static DIGEST_SUBJECT: &'static str = "flatlined: {count} hosts are {state}";
+
+/// SMTP relay alerts are mailed through.
+#[derive(Deserialize, Clone)]
+pub struct SmtpConf {
+    pub relay: String,
+    /// Defaults to 587 with STARTTLS and 25 without.
+    pub port: Option<u16>,
+    /// Upgrades the connection with STARTTLS before anything is sent, on
+    /// unless disabled.
+    pub starttls: Option<bool>,
+    pub username: Option<String>,
+    pub password: Option<Secret>,
+    pub password_file: Option<String>,
+    pub password_env: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+    /// Subject template, with the same placeholders as the alert command.
+    /// Digests fill it in from their summary and add `{count}`.
+    pub subject: Option<String>,
+    /// Body template, with the same placeholders as the alert command.
+    /// Digests repeat it for every alert.
+    pub body: Option<String>,
+}
+
+impl fmt::Debug for SmtpConf {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("SmtpConf")
+            .field("relay", &self.relay)
+            .field("port", &self.port)
+            .field("starttls", &self.starttls)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "***"))
+            .field("password_file", &self.password_file)
+            .field("password_env", &self.password_env)
+            .field("from", &self.from)
+            .field("to", &self.to)
+            .field("subject", &self.subject)
+            .field("body", &self.body)
+            .finish()
+    }
+}
+
+impl SmtpConf {
+    pub fn validate(&self) -> Result<(), String> {
+        self.from.parse::<Mailbox>().map_err(
+            |e| format!("Invalid sender {}: {}", self.from, e),
+        )?;
+        if self.to.is_empty() {
+            return Err("No mail recipients configured".to_string());
+        }
+        for t in &self.to {
+            t.parse::<Mailbox>().map_err(
+                |e| format!("Invalid recipient {}: {}", t, e),
+            )?;
+        }
+        if self.username.is_some() != self.password.is_some() {
+            return Err("SMTP username and password must be set together".to_string());
+        }
+        Ok(())
+    }
+
+    fn transport(&self, timeout: Duration) -> Result<SmtpTransport, String> {
+        let builder = if self.starttls.unwrap_or(true) {
+            SmtpTransport::starttls_relay(&self.relay)
+                .map_err(|e| e.to_string())?
+                .port(self.port.unwrap_or(587))
+        } else {
+            SmtpTransport::builder_dangerous(self.relay.as_str()).port(self.port.unwrap_or(25))
+        };
+        let builder = match (&self.username, &self.password) {
+            (Some(u), Some(p)) => builder.credentials(
+                Credentials::new(u.clone(), p.to_string()),
+            ),
+            _ => builder,
+        };
+        Ok(builder.timeout(Some(timeout)).build())
+    }
+
+    fn message(&self, notification: &Notification) -> Result<Message, String> {
+        let body = self.body.as_ref().map_or(DEFAULT_BODY, |b| b.as_str());
+        let subject = self.subject.as_deref();
+        let (subject, body) = match *notification {
+            Notification::Alert(ref a) => (
+                a.render(subject.unwrap_or(DEFAULT_SUBJECT)),
+                a.render(body),
+            ),
+            Notification::Digest(ref d) => (
+                d.render(subject.unwrap_or(DIGEST_SUBJECT)),
+                d.alerts
+                    .iter()
+                    .map(|a| a.render(body))
+                    .collect::<Vec<String>>()
+                    .join("\n"),
+            ),
+        };
+        let mut builder = Message::builder()
+            .from(self.from.parse().map_err(|e| format!("{}", e))?)
+            .subject(subject);
+        for t in &self.to {
+            builder = builder.to(t.parse().map_err(|e| format!("{}", e))?);
+        }
+        builder.body(body).map_err(|e| e.to_string())
+    }
+}
+
+/// Mails alerts to the configured recipients.
+pub struct Mailer {
+    name: String,
+    queue: Queue,
+}
+
+impl Mailer {
+    pub fn new(name: &str, smtp: &SmtpConf, conf: &FlatConf) -> Result<Mailer, String> {
+        Mailer::start(
+            name,
+            smtp,
+            Duration::from_secs(conf.alert_timeout.unwrap_or(DEFAULT_ALERT_TIMEOUT)),
+            conf.alert_retries.unwrap_or(DEFAULT_ALERT_RETRIES),
+            RETRY_DELAY,
+        )
+    }
+
+    fn start(
+        name: &str,
+        smtp: &SmtpConf,
+        timeout: Duration,
+        retries: u32,
+        retry_delay: Duration,
+    ) -> Result<Mailer, String> {
+        let transport = smtp.transport(timeout)?;
+        let smtp = smtp.clone();
+        Ok(Mailer {
+            name: name.to_string(),
+            queue: Queue::start(smtp.relay.clone(), retries, retry_delay, move |notification| {
+                let message = smtp.message(notification)?;
+                transport.send(&message).map(|_| ()).map_err(
+                    |e| e.to_string(),
+                )
+            }),
+        })
+    }
+}
+
+impl AlertSink for Mailer {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn send(&self, alert: &Alert) {
+        self.queue.send(Notification::Alert(alert.clone()));
+    }
+
+    /// Mails the digest as one message listing every alert.
+    fn send_digest(&self, digest: &Digest) {
+        self.queue.send(Notification::Digest(digest.clone()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::{self, Receiver};
+    use std::thread;
+    use testutil::alert;
+
+    fn smtp(port: u16) -> SmtpConf {
+        SmtpConf {
+            relay: "127.0.0.1".to_string(),
+            port: Some(port),
+            starttls: Some(false),
+            username: None,
+            password: None,
+            password_file: None,
+            password_env: None,
+            from: "flatlined <flat@example.com>".to_string(),
+            to: vec!["ops@example.com".to_string()],
+            subject: None,
+            body: None,
+        }
+    }
+
+    /// Minimal SMTP server accepting one session and passing on every
+    /// command and the mail data it received.
+    fn sink() -> (u16, Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            writer.write_all(b"220 localhost ESMTP\r\n").unwrap();
+            let mut data = false;
+            let mut mail = String::new();
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                if data {
+                    if line == ".\r\n" {
+                        data = false;
+                        tx.send(mail.clone()).unwrap();
+                        writer.write_all(b"250 queued\r\n").unwrap();
+                    } else {
+                        mail.push_str(&line);
+                    }
+                    continue;
+                }
+                tx.send(line.trim_end().to_string()).unwrap();
+                let reply: &[u8] = match &line[..4] {
+                    "EHLO" => b"250-localhost\r\n250 AUTH PLAIN LOGIN\r\n",
+                    "AUTH" => b"235 authenticated\r\n",
+                    "DATA" => {
+                        data = true;
+                        b"354 go ahead\r\n"
+                    }
+                    "QUIT" => {
+                        writer.write_all(b"221 bye\r\n").unwrap();
+                        break;
+                    }
+                    _ => b"250 ok\r\n",
+                };
+                writer.write_all(reply).unwrap();
+            }
+        });
+        (port, rx)
+    }
+
+    fn received(rx: &Receiver<String>) -> Vec<String> {
+        let mut lines = Vec::new();
+        while let Ok(l) = rx.recv_timeout(Duration::from_secs(5)) {
+            let quit = l == "QUIT";
+            lines.push(l);
+            if quit || lines.len() > 20 {
+                break;
+            }
+        }
+        lines
+    }
+
+    #[test]
+    fn mail_test() {
+        let (port, rx) = sink();
+        let mut conf = smtp(port);
+        conf.subject = Some("{host} went {alert}".to_string());
+        let mailer = Mailer::start("email", &conf, Duration::from_secs(5), 0, Duration::from_millis(10))
+            .unwrap();
+        mailer.send(&alert());
+
+        let lines = received(&rx);
+        assert!(lines.iter().any(|l| l == "MAIL FROM:<flat@example.com>"));
+        assert!(lines.iter().any(|l| l == "RCPT TO:<ops@example.com>"));
+        let mail = lines.iter().find(|l| l.contains("Subject:")).unwrap();
+        assert!(mail.contains("Subject: web1 went down\r\n"));
+        assert!(mail.contains("Host web1 (10.0.0.1) is DOWN, it was UP."));
+    }
+
+    #[test]
+    fn auth_test() {
+        let (port, rx) = sink();
+        let mut conf = smtp(port);
+        conf.username = Some("flat".to_string());
+        conf.password = Some(Secret::new("secret".to_string()));
+        let mailer = Mailer::start("email", &conf, Duration::from_secs(5), 0, Duration::from_millis(10))
+            .unwrap();
+        mailer.send(&alert());
+
+        let lines = received(&rx);
+        // base64 of "\0flat\0secret"
+        assert!(lines.iter().any(|l| l == "AUTH PLAIN AGZsYXQAc2VjcmV0"));
+    }
+
+    #[test]
+    fn digest_test() {
+        let (port, rx) = sink();
+        let mailer = Mailer::start("email", &smtp(port), Duration::from_secs(5), 0, Duration::from_millis(10))
+            .unwrap();
+        let mut second = alert();
+        second.host = "web2".to_string();
+        mailer.send_digest(&Digest::new(vec![alert(), second]));
+
+        let lines = received(&rx);
+        let mail = lines.iter().find(|l| l.contains("Subject:")).unwrap();
+        assert!(mail.contains("Subject: flatlined: 2 hosts are DOWN\r\n"));
+        assert!(mail.contains("Host web1 (10.0.0.1) is DOWN"));
+        assert!(mail.contains("Host web2 (10.0.0.1) is DOWN"));
+    }
+
+    #[test]
+    fn digest_subject_test() {
+        let (port, rx) = sink();
+        let mut conf = smtp(port);
+        conf.subject = Some("{count} alerts: {host} {state}".to_string());
+        let mailer = Mailer::start("email", &conf, Duration::from_secs(5), 0, Duration::from_millis(10))
+            .unwrap();
+        let mut second = alert();
+        second.host = "web2".to_string();
+        mailer.send_digest(&Digest::new(vec![alert(), second]));
+
+        let lines = received(&rx);
+        let mail = lines.iter().find(|l| l.contains("Subject:")).unwrap();
+        assert!(mail.contains("Subject: 2 alerts: web1,web2 DOWN\r\n"));
+    }
+
+    #[test]
+    fn debug_test() {
+        let mut conf = smtp(25);
+        conf.username = Some("flat".to_string());
+        conf.password = Some(Secret::new("secret".to_string()));
+        let debug = format!("{:?}", conf);
+        assert!(debug.contains("password: Some(\"***\")"));
+        assert!(!debug.contains("secret"));
+    }
+
+    #[test]
+    fn validate_test() {
+        assert!(smtp(25).validate().is_ok());
+
+        let mut conf = smtp(25);
+        conf.to.clear();
+        assert!(conf.validate().is_err());
+
+        let mut conf = smtp(25);
+        conf.from = "not an address".to_string();
+        assert!(conf.validate().is_err());
+
+        let mut conf = smtp(25);
+        conf.username = Some("flat".to_string());
+        assert!(conf.validate().is_err());
+    }
+}
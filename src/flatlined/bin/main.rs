@@ -12,32 +12,111 @@ extern crate nix;
 extern crate clap;
 extern crate ipc;
 extern crate core;
+extern crate rand;
+extern crate ed25519_dalek;
+extern crate hex;
+extern crate chacha20poly1305;
+extern crate zeroize;
+extern crate serde_json;
+extern crate ureq;
+extern crate lettre;
 
 mod flatconf;
 mod beat;
 mod socket;
 mod server;
 mod stats;
+mod replay;
+mod client;
+mod keys;
+mod keyring;
+mod status;
+mod phi;
+mod alert;
+mod executor;
+mod queue;
+mod webhook;
+mod mail;
+mod eventlog;
+mod sink;
+#[cfg(test)]
+mod testutil;
 
 use ipc::*;
-use socket::{BeatListenSocket, BeatSendSocket};
+use socket::{AckEvent, BeatListenSocket, BeatSendSocket, LISTEN_TIMEOUT};
+use beat::BeatError;
 use flatconf::FlatConf;
-use stats::Statistic;
+use stats::{HostState, Statistic, Transition};
+use alert::{Alert, Alerter};
+use eventlog::EventLog;
 use clap::{Arg, App};
 use server::Server;
+use replay::ReplayWindow;
+use keyring::Keyring;
+use keys::Secret;
+use status::Status;
+use std::collections::HashMap;
+use std::env;
 use std::process;
 use std::thread;
 use std::thread::JoinHandle;
 use std::sync::mpsc::{Sender, Receiver};
 use std::sync::mpsc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use log::LevelFilter;
 
 use std::fs;
 use std::os::unix::fs::PermissionsExt;
 
 static DEFAULT_CONF: &'static str = "/etc/flat.conf";
 static FLATSOCKPATH: &'static str = "/var/run/flatlined.sock";
+static DEFAULT_MAX_SKEW: u64 = 60;
 
 
+/// Updates the state of a host and raises the alert it is due, if any.
+fn update_state(
+    stat: &mut Statistic,
+    alerter: &mut Alerter,
+    events: &mut EventLog,
+    now: Duration,
+) -> Option<Transition> {
+    let transition = stat.update(now);
+    if let Some(t) = transition {
+        info!(
+            "{} is {} after {}s {}",
+            stat.server,
+            t.to,
+            t.duration.as_secs(),
+            t.from
+        );
+        events.transition(stat, &t);
+    }
+    if let Some(kind) = alerter.check(stat, transition, now) {
+        info!("Alerting {} for {}", kind, stat.server);
+        fire(alerter, events, &Alert::new(kind, stat, now), now);
+    }
+    transition
+}
+
+/// Fires an alert and logs it, unless it was suppressed as a duplicate.
+fn fire(alerter: &mut Alerter, events: &mut EventLog, alert: &Alert, now: Duration) {
+    if alerter.fire(alert, now) {
+        events.alert(alert);
+    }
+}
+
+/// Logs warnings and errors only, or everything down to debug messages
+/// when verbose. `RUST_LOG` takes precedence.
+fn set_log_level(verbose: bool) {
+    if env::var_os("RUST_LOG").is_none() {
+        log::set_max_level(if verbose {
+            LevelFilter::Debug
+        } else {
+            LevelFilter::Warn
+        });
+    }
+}
+
 fn ipc_handler(statistic: &[Statistic], rx: Receiver<Statistic>, flatsock: &str) -> () {
     let prefix = "ipc://".to_string();
     let mut ipc = IPC::new_bind(&(prefix + flatsock));
@@ -50,30 +129,40 @@ fn ipc_handler(statistic: &[Statistic], rx: Receiver<Statistic>, flatsock: &str)
     thread::spawn(move || loop {
         std::thread::sleep(std::time::Duration::from_millis(1000));
 
-        match rx.try_iter().last() {
-            Some(v) => {
-                if stats.is_empty() {
-                    stats.push(v.clone());
-                }
-                match stats.iter().position(|ref mut x| x.server == v.server) {
-                    Some(x) => {
-                        stats[x].send_beats = v.send_beats;
-                        stats[x].recv_beats = v.recv_beats;
-                        stats[x].set_timestamp(v.timestamp);
-                    }
-                    None => {
-                        stats.push(v.clone());
-                    }
-                }
-            }
-            None => (),
-        };
-
         let mut m = IPCMsg {
             typ: IPCMsgType::Any,
             msg: [0; 1024],
         };
-        match ipc.receive_msg().unwrap().typ {
+        let request = ipc.receive_msg().unwrap();
+        // apply every update sent since the last request, one per host
+        // and beat
+        for v in rx.try_iter() {
+            match stats.iter().position(|ref mut x| x.server.id() == v.server.id()) {
+                Some(x) => {
+                    stats[x].server = v.server.clone();
+                    stats[x].send_beats = v.send_beats;
+                    stats[x].recv_beats = v.recv_beats;
+                    stats[x].rejected_beats = v.rejected_beats;
+                    stats[x].acked_beats = v.acked_beats;
+                    stats[x].lost_beats = v.lost_beats;
+                    stats[x].rtt = v.rtt;
+                    stats[x].set_offline_after(v.offline_after);
+                    stats[x].set_detector(v.phi.clone());
+                    stats[x].set_last_seen(v.last_seen);
+                    stats[x].set_clock_offset(v.clock_offset);
+                    stats[x].set_key_id(v.key_id.clone());
+                    stats[x].set_status(v.status.clone());
+                    stats[x].state = v.state;
+                    stats[x].state_since = v.state_since;
+                    stats[x].last_transition = v.last_transition;
+                }
+                None => {
+                    stats.push(v);
+                }
+            }
+        }
+
+        match request.typ {
             IPCMsgType::Ok => {
                 m.typ = IPCMsgType::Ok;
                 m.create_payload("Ok").unwrap();
@@ -91,7 +180,18 @@ fn ipc_handler(statistic: &[Statistic], rx: Receiver<Statistic>, flatsock: &str)
                     for s in &stats {
                         ret.push_str(&s.to_string());
                     }
-                    m.create_payload(&ret).unwrap();
+                    // replies too large for one message are sent in parts
+                    let mut parts = split_payload(&ret);
+                    let last = parts.pop().unwrap();
+                    for p in parts {
+                        let mut part = IPCMsg {
+                            typ: IPCMsgType::More,
+                            msg: [0; 1024],
+                        };
+                        part.create_payload(p).unwrap();
+                        ipc.send_msg(part).unwrap();
+                    }
+                    m.create_payload(last).unwrap();
                 }
             }
             IPCMsgType::Quit => {
@@ -112,10 +212,13 @@ fn ipc_handler(statistic: &[Statistic], rx: Receiver<Statistic>, flatsock: &str)
 }
 
 fn main() {
-    env_logger::init();
+    if env::var_os("RUST_LOG").is_some() {
+        env_logger::init();
+    } else {
+        env_logger::Builder::new().filter(None, LevelFilter::Debug).init();
+        log::set_max_level(LevelFilter::Warn);
+    }
     let sr_thread: JoinHandle<_>;
-    let alerts: bool;
-    let mut command: String = "".to_string();
 
     let matches = App::new("flatlined - a heartbeat daemon")
         .version("0.1")
@@ -127,8 +230,28 @@ fn main() {
                 .help("Sets a custom config file")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("genkey")
+                .short("g")
+                .long("genkey")
+                .help("Generates a key pair for signed beats and exits"),
+        )
         .get_matches();
 
+    if matches.is_present("genkey") {
+        match keys::generate_signing_key() {
+            Ok((secret, public)) => {
+                println!("signing_key = \"{}\"", secret);
+                println!("public_key = \"{}\"", public);
+                process::exit(0);
+            }
+            Err(err) => {
+                error!("{}", err);
+                process::exit(1);
+            }
+        }
+    }
+
     let opts: FlatConf;
     match matches.value_of("config") {
         Some(x) => {
@@ -160,18 +283,29 @@ fn main() {
         Some(x) => servers = x.clone(),
         None => servers = Vec::new(),
     }
-    match nopts.command {
-        Some(c) => {
-            alerts = true;
-            command = c;
-        },
-        None => alerts = false,
-    }
+    set_log_level(opts.verbose);
+    let mut events = match EventLog::new(&opts) {
+        Ok(e) => e,
+        Err(err) => {
+            error!("{}", err);
+            process::exit(1);
+        }
+    };
+    let mut alerter = match Alerter::new(&opts) {
+        Ok(a) => a,
+        Err(err) => {
+            error!("{}", err);
+            process::exit(1);
+        }
+    };
 
     let mut stats: Vec<Statistic> = Vec::new();
     if !servers.is_empty() {
         for s in &servers {
-            stats.push(Statistic::new(s));
+            let mut stat = Statistic::new(s);
+            stat.set_offline_after(opts.offline_after(s.interval, s.missed_beats));
+            stat.set_detector(opts.detector(s.interval));
+            stats.push(stat);
         }
     }
 
@@ -191,67 +325,194 @@ fn main() {
 
     if servers.is_empty() {
         let socket = BeatListenSocket::new(&opts);
-        let mut offline_servers: Vec<Server> = Vec::new();
+        let keyring = match Keyring::new(&opts) {
+            Ok(k) => k,
+            Err(err) => {
+                error!("{}", err);
+                process::exit(1);
+            }
+        };
+        let mut windows: HashMap<String, ReplayWindow> = HashMap::new();
+        let max_skew = opts.max_skew.unwrap_or(DEFAULT_MAX_SKEW);
+        let mut last_check = Instant::now();
         sr_thread = thread::spawn(move || loop {
-            std::thread::sleep(std::time::Duration::from_millis(1000));
-
-            match stats.iter().position(
-                |ref mut x| x.is_offline(),
-            ) {
-                Some(x) => offline_servers.push(stats[x].server.clone()),
-                None => (),
-            };
-
             match socket.listen() {
-                Ok((beat, ip)) => {
-                    match beat.verify_beat(&opts.key) {
-                        Ok(_) => {
-                            match stats.iter().position(
-                                |ref mut x| x.server.address == ip.to_string(),
-                            ) {
-                                Some(x) => {
+                Ok((Ok(mut beat), addr)) => {
+                    let ip = addr.ip();
+                    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+                    match keyring.authenticate(&mut beat) {
+                        Ok(identity) => {
+                            if let Some(ref name) = identity.client {
+                                debug!("Beat from {} sent by client {}", ip, name);
+                            }
+                            let node_id = identity.client.clone().or_else(|| beat.node_id());
+                            let id = node_id.clone().unwrap_or_else(|| ip.to_string());
+                            let pos = stats.iter().position(|ref mut x| x.server.id() == id);
+                            let window = windows.entry(id).or_default();
+                            let fresh = beat.check_skew(max_skew).and_then(
+                                |_| window.check(&beat),
+                            );
+                            if fresh.is_ok() {
+                                if let Err(e) = socket.acknowledge(&beat, addr, &identity) {
+                                    warn!("Could not acknowledge beat from {}: {:?}", addr, e);
+                                }
+                            }
+                            match (pos, fresh) {
+                                (Some(x), Ok(_)) => {
+                                    if stats[x].server.address != ip.to_string() {
+                                        info!(
+                                            "{} changed address to {}",
+                                            stats[x].server,
+                                            ip
+                                        );
+                                        events.address_changed(&stats[x], &ip.to_string());
+                                        stats[x].server.address = ip.to_string();
+                                    }
                                     stats[x].incr_recv();
-                                    stats[x].set_timestamp(beat.timestamp);
+                                    stats[x].record_arrival(now);
+                                    stats[x].set_last_seen(beat.time());
+                                    stats[x].set_clock_offset(beat.clock_offset());
+                                    stats[x].set_key_id(identity.key_id);
+                                    stats[x].set_status(Status::from_beat(&beat));
+                                    update_state(&mut stats[x], &mut alerter, &mut events, now);
                                     tx.send(stats[x].clone()).unwrap();
                                 }
-                                None => {
-                                    stats.push(Statistic {
-                                        recv_beats: 1,
-                                        send_beats: 0,
-                                        server: Server {
-                                            address: ip.to_string(),
-                                            port: opts.port.clone(),
-                                            key: "".to_string(),
-                                        },
-                                        timestamp: beat.timestamp,
-                                    });
+                                (None, Ok(_)) => {
+                                    let client = opts.client.iter().flatten().find(
+                                        |c| Some(&c.name) == node_id.as_ref(),
+                                    );
+                                    let server = Server {
+                                        address: ip.to_string(),
+                                        port: opts.port.clone(),
+                                        key: Secret::default(),
+                                        key_file: None,
+                                        key_env: None,
+                                        node_id: node_id.clone(),
+                                        keys: None,
+                                        public_key: None,
+                                        interval: client.and_then(|c| c.interval),
+                                        missed_beats: client.and_then(|c| c.missed_beats),
+                                        tags: client.and_then(|c| c.tags.clone()),
+                                        severity: client.and_then(|c| c.severity),
+                                    };
+                                    let mut s = Statistic::new(&server);
+                                    s.set_offline_after(
+                                        opts.offline_after(server.interval, server.missed_beats),
+                                    );
+                                    s.set_detector(opts.detector(server.interval));
+                                    s.record_arrival(now);
+                                    s.incr_recv();
+                                    s.set_last_seen(beat.time());
+                                    s.set_clock_offset(beat.clock_offset());
+                                    s.set_key_id(identity.key_id);
+                                    s.set_status(Status::from_beat(&beat));
+                                    update_state(&mut s, &mut alerter, &mut events, now);
+                                    stats.push(s);
                                     tx.send(stats.last().unwrap().clone()).unwrap()
                                 }
+                                (Some(x), Err(e)) => {
+                                    warn!("Rejected beat from {}: {:?}", ip, e);
+                                    events.rejected(&ip.to_string(), &format!("{:?}", e));
+                                    stats[x].incr_rejected();
+                                    stats[x].set_clock_offset(beat.clock_offset());
+                                    tx.send(stats[x].clone()).unwrap();
+                                }
+                                (None, Err(e)) => {
+                                    warn!("Rejected beat from {}: {:?}", ip, e);
+                                    events.rejected(&ip.to_string(), &format!("{:?}", e));
+                                }
                             };
                         }
-                        Err(_) => println!("Could not verifiy beat"),
+                        Err(e) => {
+                            warn!("Could not verify beat from {}: {:?}", ip, e);
+                            events.rejected(&ip.to_string(), &format!("{:?}", e));
+                        }
                     }
                 }
-                Err(_) => println!("Error!"),
+                Ok((Err(e), addr)) => {
+                    warn!("Rejected datagram from {}: {:?}", addr, e);
+                    events.rejected(&addr.ip().to_string(), &format!("{:?}", e));
+                }
+                Err(BeatError::Timeout) => (),
+                Err(e) => error!("Could not receive beats: {:?}", e),
+            }
+
+            // runs on its own schedule, independent of incoming beats
+            if last_check.elapsed() < LISTEN_TIMEOUT {
+                continue;
             }
+            last_check = Instant::now();
 
-            if !offline_servers.is_empty() && alerts {
-                process::Command::new("sh").arg("-c").arg(&command).spawn()
-                    .expect("failed to start ls");
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+            for stat in &mut stats {
+                if update_state(stat, &mut alerter, &mut events, now).is_some() {
+                    tx.send(stat.clone()).unwrap();
+                }
             }
+            alerter.flush(now);
         });
     } else {
 
-        let send = BeatSendSocket::new(&opts);
+        let mut send = BeatSendSocket::new(&opts);
+        // beats lost in a row per server
+        let mut lost: Vec<u64> = vec![0; servers.len()];
+        let mut next_beat: Vec<Instant> = vec![Instant::now(); servers.len()];
 
         sr_thread = thread::spawn(move || loop {
             std::thread::sleep(std::time::Duration::from_millis(1000));
-            for (i, s) in send.conf.server.clone().iter().enumerate() {
-                match send.send(s[i].key.clone(), s[i].address.clone(), s[i].port) {
-                    Ok(_) => {
-                        stats[i].incr_send();
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+            for event in send.receive_acks() {
+                match event {
+                    AckEvent::Delivered(i, rtt) => {
+                        lost[i] = 0;
+                        stats[i].incr_acked();
+                        stats[i].set_rtt(rtt);
+                        let recovering = stats[i].time_in_state(now) < stats[i].offline_after;
+                        let next = match stats[i].state {
+                            HostState::Down => HostState::Recovered,
+                            HostState::Recovered if recovering => HostState::Recovered,
+                            _ => HostState::Up,
+                        };
+                        if let Some(t) = stats[i].enter(next, now) {
+                            events.transition(&stats[i], &t);
+                            if let Some(kind) = alerter.check(&stats[i], Some(t), now) {
+                                info!("{} acknowledges beats again", stats[i].server);
+                                fire(&mut alerter, &mut events, &Alert::new(kind, &stats[i], now), now);
+                            }
+                        }
                         tx.send(stats[i].clone()).unwrap();
                     }
+                    AckEvent::Lost(i) => {
+                        lost[i] += 1;
+                        stats[i].incr_lost();
+                        // single datagrams get lost, only as many beats in a
+                        // row as a host may miss take the server down
+                        if lost[i] >= opts.missed_beats(stats[i].server.missed_beats) {
+                            if let Some(t) = stats[i].enter(HostState::Down, now) {
+                                error!("{} did not acknowledge the last {} beats", stats[i].server, lost[i]);
+                                events.transition(&stats[i], &t);
+                                if let Some(kind) = alerter.check(&stats[i], Some(t), now) {
+                                    fire(&mut alerter, &mut events, &Alert::new(kind, &stats[i], now), now);
+                                }
+                            }
+                        }
+                        tx.send(stats[i].clone()).unwrap();
+                    }
+                }
+            }
+            alerter.flush(now);
+            for (i, stat) in stats.iter_mut().enumerate() {
+                if Instant::now() < next_beat[i] {
+                    continue;
+                }
+                next_beat[i] = Instant::now() +
+                    Duration::from_secs(opts.interval(stat.server.interval));
+                match send.send(i) {
+                    Ok(_) => {
+                        stat.incr_send();
+                        tx.send(stat.clone()).unwrap();
+                    }
+                    Err(BeatError::NoKey) => (),
                     Err(_) => error!("Send error!"),
                 }
             }
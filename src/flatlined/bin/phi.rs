@@ -0,0 +1,178 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Number of inter-arrival times kept per host.
+pub const WINDOW_SIZE: usize = 100;
+/// Lower bound of the standard deviation as a fraction of the beat interval,
+/// so a host beating like clockwork is not suspected on normal jitter.
+const MIN_STD_DEVIATION: f64 = 0.25;
+
+/// How strongly a host is suspected to have failed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Suspicion {
+    Trusted,
+    Suspect,
+    Down,
+}
+
+/// Phi-accrual failure detector.
+///
+/// Keeps the inter-arrival times of the last `WINDOW_SIZE` beats of a host,
+/// measured with the local clock, and approximates them with a normal
+/// distribution. Phi is `-log10` of the probability that a beat arrives
+/// even later than the time passed since the last one, so a phi of 1 means
+/// a 10% chance of a false suspicion, 2 means 1% and so on.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct PhiDetector {
+    intervals: VecDeque<f64>,
+    last_arrival: Option<Duration>,
+    /// Lower bound of the standard deviation in milliseconds.
+    min_std_deviation: f64,
+    suspect: f64,
+    down: f64,
+}
+
+impl PhiDetector {
+    /// A detector for a host beating every `interval`.
+    pub fn new(suspect: f64, down: f64, interval: Duration) -> PhiDetector {
+        PhiDetector {
+            intervals: VecDeque::with_capacity(WINDOW_SIZE),
+            last_arrival: None,
+            min_std_deviation: duration_ms(interval) * MIN_STD_DEVIATION,
+            suspect,
+            down,
+        }
+    }
+
+    /// Records the arrival of a beat at the given time since the epoch.
+    pub fn record(&mut self, arrival: Duration) {
+        if let Some(last) = self.last_arrival {
+            if arrival <= last {
+                return;
+            }
+            if self.intervals.len() == WINDOW_SIZE {
+                self.intervals.pop_front();
+            }
+            self.intervals.push_back(duration_ms(arrival - last));
+        }
+        self.last_arrival = Some(arrival);
+    }
+
+    /// Time the last beat arrived at, since the epoch.
+    pub fn last_arrival(&self) -> Option<Duration> {
+        self.last_arrival
+    }
+
+    fn mean(&self) -> f64 {
+        self.intervals.iter().sum::<f64>() / self.intervals.len() as f64
+    }
+
+    fn std_deviation(&self, mean: f64) -> f64 {
+        let variance = self.intervals
+            .iter()
+            .map(|i| (i - mean) * (i - mean))
+            .sum::<f64>() / self.intervals.len() as f64;
+        variance.sqrt().max(self.min_std_deviation)
+    }
+
+    /// Suspicion level at the given time since the epoch. It stays 0 until
+    /// at least two beats arrived.
+    pub fn phi(&self, now: Duration) -> f64 {
+        let last = match self.last_arrival {
+            Some(l) if !self.intervals.is_empty() => l,
+            _ => return 0.0,
+        };
+        let elapsed = match now.checked_sub(last) {
+            Some(e) => duration_ms(e),
+            None => return 0.0,
+        };
+        let mean = self.mean();
+        let y = (elapsed - mean) / self.std_deviation(mean);
+        // logistic approximation of the normal cumulative distribution
+        let e = (-y * (1.5976 + 0.070566 * y * y)).exp();
+        let phi = if elapsed > mean {
+            -(e / (1.0 + e)).log10()
+        } else {
+            -(1.0 - 1.0 / (1.0 + e)).log10()
+        };
+        if phi.is_finite() { phi } else { f64::MAX }
+    }
+
+    pub fn suspicion(&self, now: Duration) -> Suspicion {
+        let phi = self.phi(now);
+        if phi >= self.down {
+            Suspicion::Down
+        } else if phi >= self.suspect {
+            Suspicion::Suspect
+        } else {
+            Suspicion::Trusted
+        }
+    }
+}
+
+fn duration_ms(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1000.0 + f64::from(d.subsec_nanos()) / 1_000_000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detector(arrivals: &[u64]) -> PhiDetector {
+        let mut d = PhiDetector::new(5.0, 8.0, Duration::from_secs(1));
+        for a in arrivals {
+            d.record(Duration::from_millis(*a));
+        }
+        d
+    }
+
+    #[test]
+    fn regular_test() {
+        let d = detector(&[1000, 2000, 3000, 4000, 5000]);
+
+        assert!(d.phi(Duration::from_millis(5500)) < 1.0);
+        // a beat half an interval late is normal jitter
+        assert_eq!(d.suspicion(Duration::from_millis(6500)), Suspicion::Trusted);
+        assert_eq!(d.suspicion(Duration::from_millis(7100)), Suspicion::Suspect);
+        assert_eq!(d.suspicion(Duration::from_millis(7500)), Suspicion::Down);
+        assert!(d.phi(Duration::from_millis(9000)) > d.phi(Duration::from_millis(7500)));
+    }
+
+    #[test]
+    fn jitter_test() {
+        let regular = detector(&[0, 1000, 2000, 3000, 4000, 5000]);
+        let jittery = detector(&[0, 500, 2000, 2500, 4200, 5000]);
+        let now = Duration::from_millis(6500);
+
+        assert!(jittery.phi(now) < regular.phi(now));
+    }
+
+    #[test]
+    fn interval_test() {
+        let mut slow = PhiDetector::new(5.0, 8.0, Duration::from_secs(10));
+        for a in 0..6 {
+            slow.record(Duration::from_secs(a * 10));
+        }
+        let fast = detector(&[0, 1000, 2000, 3000, 4000, 5000]);
+
+        // the same relative delay raises the same suspicion
+        assert!((slow.phi(Duration::from_secs(65)) - fast.phi(Duration::from_millis(6500))).abs() < 0.01);
+        assert_eq!(slow.suspicion(Duration::from_secs(61)), Suspicion::Trusted);
+    }
+
+    #[test]
+    fn bootstrap_test() {
+        assert_eq!(detector(&[]).phi(Duration::from_secs(100)), 0.0);
+        assert_eq!(detector(&[1000]).phi(Duration::from_secs(100)), 0.0);
+        // arrivals from the past are ignored
+        assert_eq!(detector(&[2000, 1000]).phi(Duration::from_secs(100)), 0.0);
+    }
+
+    #[test]
+    fn window_test() {
+        let arrivals: Vec<u64> = (0..WINDOW_SIZE as u64 + 10).map(|i| i * 1000).collect();
+        let d = detector(&arrivals);
+
+        assert_eq!(d.intervals.len(), WINDOW_SIZE);
+    }
+}
@@ -0,0 +1,72 @@
+use alert::Notification;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::Duration;
+
+/// Notifications waiting for delivery before new ones are dropped.
+pub const QUEUE_SIZE: usize = 100;
+
+/// Delay before the first retry of a failed delivery if not overridden.
+pub const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Delivery queue of an alert sink.
+///
+/// Notifications are delivered one after another by a background thread,
+/// so a slow endpoint never holds up the beat loop. Failed deliveries are
+/// retried `retries` times, doubling the delay every time.
+pub struct Queue {
+    name: String,
+    tx: SyncSender<Notification>,
+}
+
+impl Queue {
+    pub fn start<F>(name: String, retries: u32, retry_delay: Duration, deliver: F) -> Queue
+    where
+        F: Fn(&Notification) -> Result<(), String> + Send + 'static,
+    {
+        let (tx, rx) = mpsc::sync_channel(QUEUE_SIZE);
+        let worker = name.clone();
+        thread::spawn(move || run(&worker, rx, retries, retry_delay, deliver));
+        Queue { name, tx }
+    }
+
+    /// Queues a notification for delivery.
+    pub fn send(&self, notification: Notification) {
+        match self.tx.try_send(notification) {
+            Ok(_) => (),
+            Err(TrySendError::Full(n)) => {
+                error!("Alert queue for {} is full, dropping {}", self.name, n)
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                error!("Alert delivery to {} has stopped", self.name)
+            }
+        }
+    }
+}
+
+fn run<F>(name: &str, rx: Receiver<Notification>, retries: u32, retry_delay: Duration, deliver: F)
+where
+    F: Fn(&Notification) -> Result<(), String>,
+{
+    for notification in rx {
+        let mut delay = retry_delay;
+        for attempt in 0..retries + 1 {
+            if attempt > 0 {
+                thread::sleep(delay);
+                delay *= 2;
+            }
+            match deliver(&notification) {
+                Ok(_) => {
+                    debug!("Delivered {} to {}", notification, name);
+                    break;
+                }
+                Err(e) if attempt < retries => {
+                    warn!("Could not deliver alert to {}: {}, retrying", name, e)
+                }
+                Err(e) => {
+                    error!("Could not deliver {} to {}: {}", notification, name, e)
+                }
+            }
+        }
+    }
+}
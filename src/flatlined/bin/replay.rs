@@ -0,0 +1,152 @@
+use beat::{Beat, BeatError};
+
+/// Number of sequence numbers below the highest one that are still accepted.
+pub const WINDOW_SIZE: u64 = 64;
+
+/// Sliding replay window for the beats of a single sender.
+///
+/// Every sender picks a random nonce on startup and numbers its beats within
+/// that session. A beat is accepted once per sequence number as long as it is
+/// not older than `WINDOW_SIZE` beats. A new nonce starts a new session, but
+/// only if the beat is newer than everything accepted so far, so beats of old
+/// sessions cannot be replayed. Legacy beats carry no sequence number and
+/// have to be newer than every beat accepted before.
+#[derive(Debug, Clone, Default)]
+pub struct ReplayWindow {
+    seen: bool,
+    nonce: u64,
+    highest: u64,
+    bitmap: u64,
+    timestamp: u64,
+}
+
+impl ReplayWindow {
+    /// Checks a verified beat and records it as seen.
+    pub fn check(&mut self, beat: &Beat) -> Result<(), BeatError> {
+        if beat.is_legacy() {
+            return self.check_timestamp(beat.timestamp);
+        }
+        match beat.sequence() {
+            Some((nonce, seq)) => self.check_sequence(nonce, seq, beat.timestamp),
+            None => Err(BeatError::Malformed),
+        }
+    }
+
+    fn check_timestamp(&mut self, timestamp: u64) -> Result<(), BeatError> {
+        if timestamp <= self.timestamp {
+            return Err(BeatError::Stale);
+        }
+        self.timestamp = timestamp;
+        Ok(())
+    }
+
+    fn check_sequence(&mut self, nonce: u64, seq: u64, timestamp: u64) -> Result<(), BeatError> {
+        if !self.seen || nonce != self.nonce {
+            if self.seen && timestamp <= self.timestamp {
+                return Err(BeatError::Stale);
+            }
+            self.seen = true;
+            self.nonce = nonce;
+            self.highest = seq;
+            self.bitmap = 1;
+            self.timestamp = timestamp;
+            return Ok(());
+        }
+
+        if seq > self.highest {
+            let shift = seq - self.highest;
+            self.bitmap = if shift >= WINDOW_SIZE {
+                1
+            } else {
+                (self.bitmap << shift) | 1
+            };
+            self.highest = seq;
+        } else {
+            let offset = self.highest - seq;
+            if offset >= WINDOW_SIZE {
+                return Err(BeatError::Stale);
+            }
+            if self.bitmap & (1 << offset) != 0 {
+                return Err(BeatError::Replayed);
+            }
+            self.bitmap |= 1 << offset;
+        }
+
+        if timestamp > self.timestamp {
+            self.timestamp = timestamp;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use beat::{Extension, LEGACY_VERSION};
+
+    #[test]
+    fn duplicate_test() {
+        let mut w = ReplayWindow::default();
+        let b = Beat::with_extensions("foo", vec![Extension::sequence(1, 1)]);
+
+        assert!(w.check(&b).is_ok());
+        assert!(w.check(&b).is_err());
+    }
+
+    #[test]
+    fn out_of_order_test() {
+        let mut w = ReplayWindow::default();
+
+        assert!(w.check_sequence(1, 10, 100).is_ok());
+        assert!(w.check_sequence(1, 12, 100).is_ok());
+        assert!(w.check_sequence(1, 11, 100).is_ok());
+        assert!(w.check_sequence(1, 11, 100).is_err());
+        assert!(w.check_sequence(1, 12, 100).is_err());
+    }
+
+    #[test]
+    fn stale_test() {
+        let mut w = ReplayWindow::default();
+
+        assert!(w.check_sequence(1, 1, 100).is_ok());
+        assert!(w.check_sequence(1, 1 + WINDOW_SIZE, 101).is_ok());
+        assert!(w.check_sequence(1, 1, 100).is_err());
+        assert!(w.check_sequence(1, 2, 100).is_ok());
+    }
+
+    #[test]
+    fn new_session_test() {
+        let mut w = ReplayWindow::default();
+
+        assert!(w.check_sequence(1, 500, 100).is_ok());
+        // restarted sender
+        assert!(w.check_sequence(2, 1, 200).is_ok());
+        // replayed beat of the previous session
+        assert!(w.check_sequence(1, 501, 101).is_err());
+        assert!(w.check_sequence(2, 2, 201).is_ok());
+    }
+
+    #[test]
+    fn legacy_test() {
+        let mut w = ReplayWindow::default();
+        let mut b = Beat::new("foo");
+        b.version = LEGACY_VERSION;
+        b.timestamp = 100;
+
+        assert!(w.check(&b).is_ok());
+        assert!(w.check(&b).is_err());
+        b.timestamp = 101;
+        assert!(w.check(&b).is_ok());
+        // older than the newest sequenced beat
+        assert!(w.check_sequence(1, 1, 200).is_ok());
+        b.timestamp = 150;
+        assert!(w.check(&b).is_err());
+    }
+
+    #[test]
+    fn missing_sequence_test() {
+        let mut w = ReplayWindow::default();
+
+        assert!(w.check(&Beat::new("foo")).is_err());
+    }
+}
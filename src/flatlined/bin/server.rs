@@ -1,17 +1,93 @@
 use std::fmt;
+use alert::AlertSeverity;
+use keys::{KeyEntry, Secret};
 
 #[derive(Debug, Deserialize, Clone, PartialEq)]
 pub struct Server {
     pub address: String,
     pub port: u16,
-    pub key: String,
+    #[serde(default)]
+    pub key: Secret,
+    pub key_file: Option<String>,
+    pub key_env: Option<String>,
+    pub node_id: Option<String>,
+    pub keys: Option<Vec<KeyEntry>>,
+    /// Hex encoded Ed25519 public key the server signs acknowledgements
+    /// with.
+    pub public_key: Option<String>,
+    /// Seconds between two beats to or from this host.
+    pub interval: Option<u64>,
+    /// Beats this host may miss before it is considered offline.
+    pub missed_beats: Option<u64>,
+    /// Tags alerts for this host can be routed by.
+    pub tags: Option<Vec<String>>,
+    /// Severity of down alerts for this host.
+    pub severity: Option<AlertSeverity>,
+}
+
+impl Server {
+    /// The identity statistics are kept under: the node ID if known, the
+    /// address otherwise.
+    pub fn id(&self) -> &str {
+        match self.node_id {
+            Some(ref n) => n,
+            None => &self.address,
+        }
+    }
+
+    /// A copy without any key material, for statistics that are passed
+    /// around and shown.
+    pub fn without_keys(&self) -> Server {
+        Server {
+            key: Secret::default(),
+            keys: None,
+            ..self.clone()
+        }
+    }
 }
 
 impl fmt::Display for Server {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref n) = self.node_id {
+            try!(fmt.write_str(n));
+            try!(fmt.write_str("@"));
+        }
         try!(fmt.write_str(&self.address));
         try!(fmt.write_str(":"));
         try!(fmt.write_str(&self.port.to_string()));
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate toml;
+
+    use super::*;
+    use testutil::server;
+
+    #[test]
+    fn id_test() {
+        let mut s = server();
+        assert_eq!(s.id(), "10.0.0.1");
+        assert_eq!(s.to_string(), "10.0.0.1:8888");
+
+        s.node_id = Some("alpha".to_string());
+        assert_eq!(s.id(), "alpha");
+        assert_eq!(s.to_string(), "alpha@10.0.0.1:8888");
+    }
+
+    #[test]
+    fn without_keys_test() {
+        let input = "address = '10.0.0.1' \n port = 8888 \n key = 'secret' \n \
+                         [[keys]] \n id = 'next' \n key = 'next-secret' \n"
+            .to_string();
+        let s: Server = toml::from_str(&input).unwrap();
+        assert!(!format!("{:?}", s).contains("secret"));
+
+        let stripped = s.without_keys();
+        assert!(stripped.key.is_empty());
+        assert_eq!(stripped.keys, None);
+        assert_eq!(stripped.to_string(), s.to_string());
+    }
+}
@@ -0,0 +1,449 @@
+use alert::{Alert, AlertKind, AlertSeverity, Digest};
+use eventlog::EventLog;
+use executor::Executor;
+use flatconf::FlatConf;
+use mail::{Mailer, SmtpConf};
+use std::collections::{HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+use webhook::Webhook;
+
+/// Names of the sinks made from the top-level `command`, `webhook` and
+/// `smtp` settings.
+pub static COMMAND_SINK: &'static str = "command";
+pub static WEBHOOK_SINK: &'static str = "webhook";
+pub static EMAIL_SINK: &'static str = "email";
+
+/// Period rate limits are counted over.
+const RATE_PERIOD: Duration = Duration::from_secs(60);
+
+/// Something alerts are delivered to.
+pub trait AlertSink: Send {
+    fn name(&self) -> &str;
+
+    /// Hands an alert over for delivery. Sinks talking to the outside
+    /// world queue it instead of blocking the caller.
+    fn send(&self, alert: &Alert);
+
+    /// Hands over alerts of one kind raised together, to be delivered as a
+    /// single notification. Sinks without a format of their own for it get
+    /// the summary of the digest.
+    fn send_digest(&self, digest: &Digest) {
+        self.send(&digest.summary());
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SinkKind {
+    Command,
+    Webhook,
+    Email,
+    Log,
+}
+
+/// A named alert sink in the configuration.
+#[derive(Debug, Deserialize, Clone)]
+pub struct SinkConf {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: SinkKind,
+    /// Command run for down alerts, for `command` sinks.
+    pub command: Option<String>,
+    /// Command run for recovery alerts, for `command` sinks.
+    pub recovery_command: Option<String>,
+    /// URL alerts are posted to, for `webhook` sinks.
+    pub url: Option<String>,
+    /// Relay and recipients, for `email` sinks.
+    pub smtp: Option<SmtpConf>,
+    /// File alerts are appended to, for `log` sinks. They are sent to the
+    /// syslog socket without one.
+    pub path: Option<String>,
+    /// Notifications per minute, the top-level `rate_limit` if left out.
+    pub rate_limit: Option<u32>,
+}
+
+impl SinkConf {
+    fn validate(&self) -> Result<(), String> {
+        let missing = |field: &str| format!("Sink {}: {} is missing", self.name, field);
+        match self.kind {
+            SinkKind::Command => {
+                if self.command.is_none() && self.recovery_command.is_none() {
+                    return Err(missing("command"));
+                }
+            }
+            SinkKind::Webhook => {
+                let url = self.url.as_ref().ok_or_else(|| missing("url"))?;
+                check_url(url)?;
+            }
+            SinkKind::Email => {
+                self.smtp
+                    .as_ref()
+                    .ok_or_else(|| missing("smtp"))?
+                    .validate()
+                    .map_err(|e| format!("Sink {}: {}", self.name, e))?;
+            }
+            SinkKind::Log => (),
+        }
+        if self.rate_limit == Some(0) {
+            return Err(format!("Sink {}: rate_limit must be at least 1", self.name));
+        }
+        Ok(())
+    }
+}
+
+/// Sends alerts matching all of its criteria to a set of sinks. Criteria
+/// left out match every alert.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct Route {
+    /// Host IDs, `*` matches any number of characters.
+    pub hosts: Option<Vec<String>>,
+    /// Matches hosts carrying any of the tags.
+    pub tags: Option<Vec<String>>,
+    pub severities: Option<Vec<AlertSeverity>>,
+    pub sinks: Vec<String>,
+}
+
+impl Route {
+    pub fn matches(&self, alert: &Alert) -> bool {
+        let host = self.hosts.iter().all(
+            |h| h.iter().any(|p| glob(p, &alert.host)),
+        );
+        let tag = self.tags.iter().all(
+            |t| t.iter().any(|t| alert.tags.contains(t)),
+        );
+        let severity = self.severities.iter().all(|s| s.contains(&alert.severity));
+        host && tag && severity
+    }
+}
+
+/// Matches text against a pattern in which `*` stands for any number of
+/// characters.
+fn glob(pattern: &str, text: &str) -> bool {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    if parts.len() == 1 {
+        return pattern == text;
+    }
+    let first = parts[0];
+    let last = parts[parts.len() - 1];
+    if !text.starts_with(first) || text.len() < first.len() + last.len() ||
+        !text.ends_with(last)
+    {
+        return false;
+    }
+    let mut rest = &text[first.len()..text.len() - last.len()];
+    for part in &parts[1..parts.len() - 1] {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+pub fn check_url(url: &str) -> Result<(), String> {
+    if url.starts_with("http://") || url.starts_with("https://") {
+        Ok(())
+    } else {
+        // the URL is left out, it may carry a token
+        Err("Unsupported webhook URL, it must start with http:// or https://".to_string())
+    }
+}
+
+/// Number of notifications a sink may send per minute, if limited.
+pub fn rate_limit(conf: &FlatConf, sink: &str) -> Option<u32> {
+    conf.sink
+        .iter()
+        .flatten()
+        .find(|s| s.name == sink)
+        .and_then(|s| s.rate_limit)
+        .or(conf.rate_limit)
+}
+
+/// Counts the notifications sent to a sink within the last minute.
+pub struct RateLimit {
+    limit: usize,
+    sent: VecDeque<Duration>,
+}
+
+impl RateLimit {
+    pub fn new(limit: u32) -> RateLimit {
+        RateLimit {
+            limit: limit as usize,
+            sent: VecDeque::new(),
+        }
+    }
+
+    /// Whether another notification may be sent at the given local time,
+    /// counting it if so.
+    pub fn allow(&mut self, now: Duration) -> bool {
+        while let Some(&t) = self.sent.front() {
+            if now.checked_sub(t).unwrap_or_default() < RATE_PERIOD {
+                break;
+            }
+            self.sent.pop_front();
+        }
+        if self.sent.len() >= self.limit {
+            return false;
+        }
+        self.sent.push_back(now);
+        true
+    }
+}
+
+/// Checks the sinks and routes of a configuration.
+pub fn validate(conf: &FlatConf) -> Result<(), String> {
+    if conf.rate_limit == Some(0) {
+        return Err("rate_limit must be at least 1".to_string());
+    }
+    let mut names = HashSet::new();
+    if conf.command.is_some() || conf.recovery_command.is_some() {
+        names.insert(COMMAND_SINK.to_string());
+    }
+    if let Some(ref w) = conf.webhook {
+        check_url(w)?;
+        names.insert(WEBHOOK_SINK.to_string());
+    }
+    if let Some(ref s) = conf.smtp {
+        s.validate()?;
+        names.insert(EMAIL_SINK.to_string());
+    }
+    for s in conf.sink.iter().flatten() {
+        s.validate()?;
+        if !names.insert(s.name.clone()) {
+            return Err(format!("Duplicate sink {}", s.name));
+        }
+    }
+    for r in conf.route.iter().flatten() {
+        if r.sinks.is_empty() {
+            return Err("Route without sinks".to_string());
+        }
+        if let Some(s) = r.sinks.iter().find(|s| !names.contains(*s)) {
+            return Err(format!("Route to unknown sink {}", s));
+        }
+    }
+    Ok(())
+}
+
+/// Runs alert commands with the alert context in their environment.
+pub struct CommandSink {
+    name: String,
+    command: Option<String>,
+    recovery_command: Option<String>,
+    executor: Executor,
+}
+
+impl CommandSink {
+    fn template(&self, kind: AlertKind) -> Option<&String> {
+        match kind {
+            AlertKind::Down => self.command.as_ref(),
+            AlertKind::Recovery => self.recovery_command.as_ref(),
+        }
+    }
+
+    fn run(&self, command: String, variables: Vec<(&str, String)>) {
+        let env = variables
+            .into_iter()
+            .map(|(name, value)| (format!("FLAT_{}", name.to_uppercase()), value))
+            .collect();
+        self.executor.run(command, env);
+    }
+}
+
+impl AlertSink for CommandSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn send(&self, alert: &Alert) {
+        if let Some(t) = self.template(alert.kind) {
+            self.run(alert.expand(t), alert.variables());
+        }
+    }
+
+    /// Runs the command once for the summary of the digest, with the number
+    /// of alerts in `FLAT_COUNT`.
+    fn send_digest(&self, digest: &Digest) {
+        if let Some(t) = self.template(digest.kind) {
+            self.run(digest.expand(t), digest.variables());
+        }
+    }
+}
+
+/// Records alerts in an event log of their own.
+pub struct LogSink {
+    name: String,
+    events: Mutex<EventLog>,
+}
+
+impl AlertSink for LogSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn send(&self, alert: &Alert) {
+        self.events.lock().unwrap().alert(alert);
+    }
+
+    /// Records every alert of the digest on its own.
+    fn send_digest(&self, digest: &Digest) {
+        let mut events = self.events.lock().unwrap();
+        for a in &digest.alerts {
+            events.alert(a);
+        }
+    }
+}
+
+/// Builds the configured sinks. Command sinks share one executor, so its
+/// limit on concurrent commands holds for all of them.
+pub fn sinks(conf: &FlatConf) -> Result<Vec<Box<dyn AlertSink>>, String> {
+    let executor = Executor::new(conf);
+    let mut sinks: Vec<Box<dyn AlertSink>> = Vec::new();
+    if conf.command.is_some() || conf.recovery_command.is_some() {
+        sinks.push(Box::new(CommandSink {
+            name: COMMAND_SINK.to_string(),
+            command: conf.command.clone(),
+            recovery_command: conf.recovery_command.clone(),
+            executor: executor.clone(),
+        }));
+    }
+    if let Some(ref url) = conf.webhook {
+        sinks.push(Box::new(Webhook::new(WEBHOOK_SINK, url, conf)));
+    }
+    if let Some(ref smtp) = conf.smtp {
+        sinks.push(Box::new(Mailer::new(EMAIL_SINK, smtp, conf)?));
+    }
+    for s in conf.sink.iter().flatten() {
+        let sink: Box<dyn AlertSink> = match s.kind {
+            SinkKind::Command => Box::new(CommandSink {
+                name: s.name.clone(),
+                command: s.command.clone(),
+                recovery_command: s.recovery_command.clone(),
+                executor: executor.clone(),
+            }),
+            SinkKind::Webhook => {
+                let url = s.url.as_ref().ok_or(format!("Sink {}: url is missing", s.name))?;
+                Box::new(Webhook::new(&s.name, url, conf))
+            }
+            SinkKind::Email => {
+                let smtp = s.smtp.as_ref().ok_or(format!("Sink {}: smtp is missing", s.name))?;
+                Box::new(Mailer::new(&s.name, smtp, conf)?)
+            }
+            SinkKind::Log => Box::new(LogSink {
+                name: s.name.clone(),
+                events: Mutex::new(EventLog::open(s.path.as_deref(), conf)?),
+            }),
+        };
+        sinks.push(sink);
+    }
+    Ok(sinks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testutil::{self, parse};
+
+    fn alert(host: &str, tags: &[&str], severity: AlertSeverity) -> Alert {
+        let mut alert = testutil::alert();
+        alert.host = host.to_string();
+        alert.node_id = Some(host.to_string());
+        alert.severity = severity;
+        alert.tags = tags.iter().map(|t| t.to_string()).collect();
+        alert
+    }
+
+    #[test]
+    fn glob_test() {
+        assert!(glob("web1", "web1"));
+        assert!(!glob("web1", "web10"));
+        assert!(glob("web*", "web10"));
+        assert!(glob("*.example.com", "db.example.com"));
+        assert!(glob("db*-*prod", "db1-eu-prod"));
+        assert!(!glob("db*-*prod", "db1prod"));
+        assert!(!glob("a*a", "a"));
+        assert!(glob("*", ""));
+    }
+
+    #[test]
+    fn route_test() {
+        let route = Route {
+            hosts: Some(vec!["web*".to_string()]),
+            tags: Some(vec!["frontend".to_string(), "edge".to_string()]),
+            severities: Some(vec![AlertSeverity::Critical]),
+            sinks: vec!["pager".to_string()],
+        };
+
+        assert!(route.matches(&alert("web1", &["edge"], AlertSeverity::Critical)));
+        assert!(!route.matches(&alert("db1", &["edge"], AlertSeverity::Critical)));
+        assert!(!route.matches(&alert("web1", &["db"], AlertSeverity::Critical)));
+        assert!(!route.matches(&alert("web1", &["edge"], AlertSeverity::Warning)));
+
+        let catch_all = Route {
+            hosts: None,
+            tags: None,
+            severities: None,
+            sinks: vec!["pager".to_string()],
+        };
+        assert!(catch_all.matches(&alert("db1", &[], AlertSeverity::Info)));
+    }
+
+    #[test]
+    fn conf_test() {
+        let c = parse(
+            "command = 'page.sh' \n \
+             [[sink]] \n name = 'web-team' \n type = 'webhook' \n url = 'https://chat.example.com/' \n \
+             [[sink]] \n name = 'audit' \n type = 'log' \n \
+             [[route]] \n tags = ['web'] \n sinks = ['web-team', 'command'] \n \
+             [[route]] \n severities = ['critical', 'warning'] \n sinks = ['audit'] \n",
+        ).unwrap();
+        assert_eq!(c.sink.as_ref().unwrap()[0].kind, SinkKind::Webhook);
+        assert_eq!(
+            c.route.as_ref().unwrap()[1].severities,
+            Some(vec![AlertSeverity::Critical, AlertSeverity::Warning])
+        );
+        let names: Vec<String> = sinks(&c).unwrap().iter().map(|s| s.name().to_string()).collect();
+        assert_eq!(names, vec!["command", "web-team", "audit"]);
+    }
+
+    #[test]
+    fn invalid_conf_test() {
+        // unknown sink
+        assert!(parse("[[route]] \n sinks = ['pager'] \n").is_err());
+        // duplicate name, also with the top-level command
+        assert!(
+            parse(
+                "[[sink]] \n name = 'a' \n type = 'log' \n [[sink]] \n name = 'a' \n type = 'log' \n",
+            ).is_err()
+        );
+        assert!(
+            parse("command = 'page.sh' \n [[sink]] \n name = 'command' \n type = 'log' \n").is_err()
+        );
+        // missing settings
+        assert!(parse("[[sink]] \n name = 'a' \n type = 'webhook' \n").is_err());
+        assert!(parse("[[sink]] \n name = 'a' \n type = 'command' \n").is_err());
+        assert!(parse("[[sink]] \n name = 'a' \n type = 'email' \n").is_err());
+        assert!(parse("[[sink]] \n name = 'a' \n type = 'pigeon' \n").is_err());
+        // no notifications at all
+        assert!(parse("rate_limit = 0 \n").is_err());
+        assert!(parse("[[sink]] \n name = 'a' \n type = 'log' \n rate_limit = 0 \n").is_err());
+    }
+
+    #[test]
+    fn rate_limit_test() {
+        let c = parse(
+            "command = 'page.sh' \n rate_limit = 10 \n \
+             [[sink]] \n name = 'pager' \n type = 'log' \n rate_limit = 2 \n",
+        ).unwrap();
+        assert_eq!(rate_limit(&c, "pager"), Some(2));
+        assert_eq!(rate_limit(&c, COMMAND_SINK), Some(10));
+
+        let mut limit = RateLimit::new(2);
+        assert!(limit.allow(Duration::from_secs(0)));
+        assert!(limit.allow(Duration::from_secs(30)));
+        assert!(!limit.allow(Duration::from_secs(59)));
+        assert!(limit.allow(Duration::from_secs(60)));
+        assert!(!limit.allow(Duration::from_secs(89)));
+        assert!(limit.allow(Duration::from_secs(90)));
+    }
+}
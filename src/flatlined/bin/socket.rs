@@ -2,20 +2,64 @@ use std::net::UdpSocket;
 use flatconf::FlatConf;
 use beat::*;
 use log::*;
-use std::error::Error;
 use std::net::*;
 use trust_dns_resolver::Resolver;
+use rand;
+use ed25519_dalek::SigningKey;
+use keys;
+use keys::KeySet;
+use keyring::Identity;
+use server::Server;
+use status::Status;
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::time::*;
+use nix::unistd;
+
+/// Seconds a sender waits for the acknowledgement of a beat by default.
+pub const DEFAULT_ACK_TIMEOUT: u64 = 5;
+/// Longest time `BeatListenSocket::listen` blocks without receiving a beat.
+pub const LISTEN_TIMEOUT: Duration = Duration::from_secs(1);
 
 pub struct BeatListenSocket {
     socket: UdpSocket,
+    accept_legacy: bool,
+    ack: bool,
+    signing_key: Option<SigningKey>,
 }
 
 pub struct BeatSendSocket {
     socket: UdpSocket,
     pub conf: FlatConf,
+    pub servers: Vec<Server>,
+    /// Keys of the servers, in the same order.
+    keys: Vec<KeySet>,
+    nonce: u64,
+    sequence: u64,
+    signing_key: Option<SigningKey>,
+    encrypt: bool,
+    send_status: bool,
+    node_id: Option<String>,
+    ack: bool,
+    ack_timeout: Duration,
+    /// Beats waiting for their acknowledgement: sequence number to index of
+    /// the server and time sent.
+    pending: HashMap<u64, (usize, Instant)>,
+}
+
+/// Outcome of a beat sent with acknowledgements enabled.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AckEvent {
+    /// The server with the given index acknowledged a beat after the given
+    /// round-trip time.
+    Delivered(usize, Duration),
+    /// A beat to the server with the given index was not acknowledged in
+    /// time.
+    Lost(usize),
 }
 
-pub type BeatResult = Result<(Beat, IpAddr), BeatError>;
+/// A received datagram and its sender, with the beat or why it is none.
+pub type BeatResult = Result<(Result<Beat, BeatError>, SocketAddr), BeatError>;
 pub type BeatSendResult = Result<(), BeatError>;
 
 impl BeatListenSocket {
@@ -32,32 +76,113 @@ impl BeatListenSocket {
     }
 
     pub fn new(conf: &FlatConf) -> BeatListenSocket {
-        BeatListenSocket { socket: BeatListenSocket::bind(conf.port) }
+        let socket = BeatListenSocket::bind(conf.port);
+        socket.set_read_timeout(Some(LISTEN_TIMEOUT)).unwrap();
+        BeatListenSocket {
+            socket,
+            accept_legacy: conf.accept_legacy.unwrap_or(false),
+            ack: conf.ack.unwrap_or(false),
+            signing_key: conf.signing_key.as_ref().map(|k| {
+                keys::signing_key_from_hex(k).unwrap()
+            }),
+        }
     }
 
+    /// Waits for the next beat. Returns `BeatError::Timeout` if none arrives
+    /// within `LISTEN_TIMEOUT`, so callers get to run periodic work even
+    /// when no sender is alive. Datagrams that are no acceptable beat come
+    /// with their sender, so they can be reported.
     pub fn listen(&self) -> BeatResult {
-        let mut buf = [0; 72];
+        let mut buf = [0; MAX_BEAT_SIZE];
         match self.socket.recv_from(&mut buf) {
-            Ok((count, addr)) => {
-                if count == 72 {
-                    if log_enabled!(Level::Debug) {
-                        debug!("Beat received.");
-                    }
-                    Ok((Beat::from_bytes(buf), addr.ip()))
-                } else {
-                    Err(BeatError::WrongSize)
-                }
+            Ok((count, addr)) => Ok((self.parse(&buf[..count]), addr)),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                Err(BeatError::Timeout)
             }
             Err(_) => Err(BeatError::ListenError),
         }
     }
+
+    fn parse(&self, datagram: &[u8]) -> Result<Beat, BeatError> {
+        let beat = Beat::from_bytes(datagram)?;
+        if beat.is_legacy() && !self.accept_legacy {
+            return Err(BeatError::LegacyRejected);
+        }
+        if beat.is_ack() {
+            return Err(BeatError::Malformed);
+        }
+        if log_enabled!(Level::Debug) {
+            debug!("Beat version {} received.", beat.version);
+        }
+        Ok(beat)
+    }
+
+    /// Acknowledges an accepted beat. The acknowledgement is signed with the
+    /// server's signing key if it has one, otherwise it is authenticated
+    /// with the key the beat was verified with. Legacy beats and beats of
+    /// senders that only sign are not acknowledged without a signing key.
+    pub fn acknowledge(&self, beat: &Beat, addr: SocketAddr, identity: &Identity) -> BeatSendResult {
+        if !self.ack {
+            return Ok(());
+        }
+        let (nonce, seq) = match beat.sequence() {
+            Some(s) => s,
+            None => return Ok(()),
+        };
+        let mut ack = Beat::acknowledgement(nonce, seq);
+        match (self.signing_key.as_ref(), identity.key.as_ref()) {
+            (Some(k), _) => ack.sign(k),
+            (None, Some(k)) => {
+                if let Some(ref id) = identity.key_id {
+                    ack.extensions.push(Extension::key_id(id));
+                }
+                ack.authenticate(k);
+            }
+            (None, None) => return Ok(()),
+        }
+        match self.socket.send_to(&ack.into_bytes(), addr) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(BeatError::SendError),
+        }
+    }
 }
 
 impl BeatSendSocket {
     pub fn new(conf: &FlatConf) -> BeatSendSocket {
+        let ack = conf.ack.unwrap_or(false);
+        let socket = UdpSocket::bind(("0.0.0.0", conf.port)).unwrap();
+        if ack {
+            socket.set_nonblocking(true).unwrap();
+        }
+        let servers = conf.server.clone().unwrap_or_default();
+        let keys = servers
+            .iter()
+            .map(|s| KeySet::new(Some(&s.key), &s.keys).unwrap())
+            .collect();
         BeatSendSocket {
-            socket: UdpSocket::bind(("0.0.0.0", conf.port)).unwrap(),
+            socket,
             conf: conf.clone(),
+            servers,
+            keys,
+            nonce: rand::random(),
+            sequence: 0,
+            signing_key: conf.signing_key.as_ref().map(|k| {
+                keys::signing_key_from_hex(k).unwrap()
+            }),
+            encrypt: conf.encryption.is_some(),
+            send_status: conf.send_status.unwrap_or(false),
+            node_id: conf.node_id.clone().or_else(BeatSendSocket::hostname),
+            ack,
+            ack_timeout: Duration::from_secs(conf.ack_timeout.unwrap_or(DEFAULT_ACK_TIMEOUT)),
+            pending: HashMap::new(),
+        }
+    }
+
+    fn hostname() -> Option<String> {
+        let mut buf = [0u8; 256];
+        match unistd::gethostname(&mut buf) {
+            Ok(name) => name.to_str().ok().map(|n| n.to_string()),
+            Err(_) => None,
         }
     }
 
@@ -76,19 +201,62 @@ impl BeatSendSocket {
         }
     }
 
-    pub fn send(&self, key: String, addr: String, port: u16) -> BeatSendResult {
-        let msg = Beat::new(key.as_str()).into_bytes();
-        match BeatSendSocket::get_ip(&addr) {
+    /// Sends a beat to the server with the given index. Returns
+    /// `BeatError::NoKey` without sending anything if none of its keys is
+    /// valid at the moment.
+    pub fn send(&mut self, index: usize) -> BeatSendResult {
+        let server = self.servers[index].clone();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let (key_id, key) = match self.keys[index].current(now) {
+            Some(k) => k,
+            None => {
+                warn!("No key for {} is valid now, skipping beat", server);
+                return Err(BeatError::NoKey);
+            }
+        };
+
+        self.sequence += 1;
+        let mut ext = vec![Extension::sequence(self.nonce, self.sequence)];
+        if self.send_status {
+            ext.push(Status::collect(&self.conf).to_extension());
+        }
+        let mut clear = Vec::new();
+        if let Some(ref n) = self.node_id {
+            clear.push(Extension::node_id(n));
+        }
+        if let Some(id) = key_id {
+            clear.push(Extension::key_id(id));
+        }
+        let mut beat;
+        if self.encrypt {
+            beat = Beat::unsigned(clear);
+            beat.seal(&keys::encryption_key(key), ext);
+        } else {
+            beat = Beat::unsigned(clear);
+            beat.extensions.extend(ext);
+        }
+        match self.signing_key {
+            Some(ref k) => beat.sign(k),
+            None => beat.authenticate(key),
+        }
+        let msg = beat.into_bytes();
+        match BeatSendSocket::get_ip(&server.address) {
             Ok(ip) => {
-                match self.socket.send_to(&msg, (ip, port)) {
+                match self.socket.send_to(&msg, (ip, server.port)) {
                     Ok(send) => {
                         if log_enabled!(Level::Debug) {
                             debug!("Send {} bytes!", send);
                         }
+                        if self.ack {
+                            self.pending.insert(self.sequence, (index, Instant::now()));
+                        }
                         Ok(())
                     }
                     Err(e) => {
-                        println!("{}", e.description());
+                        error!("Could not send beat to {}: {}", server, e);
                         Err(BeatError::SendError)
                     }
                 }
@@ -98,12 +266,180 @@ impl BeatSendSocket {
             }
         }
     }
+
+    /// Reads the acknowledgements received since the last call and reports
+    /// beats that were not acknowledged within the timeout as lost.
+    pub fn receive_acks(&mut self) -> Vec<AckEvent> {
+        let mut ret = Vec::new();
+        if !self.ack {
+            return ret;
+        }
+
+        let mut buf = [0; MAX_BEAT_SIZE];
+        while let Ok((count, addr)) = self.socket.recv_from(&mut buf) {
+            match self.check_ack(&buf[..count]) {
+                Ok(event) => ret.push(event),
+                Err(e) => warn!("Rejected acknowledgement from {}: {:?}", addr, e),
+            }
+        }
+
+        let timeout = self.ack_timeout;
+        let mut lost: Vec<u64> = self.pending
+            .iter()
+            .filter(|&(_, &(_, sent))| sent.elapsed() > timeout)
+            .map(|(&seq, _)| seq)
+            .collect();
+        lost.sort();
+        for seq in lost {
+            if let Some((index, _)) = self.pending.remove(&seq) {
+                ret.push(AckEvent::Lost(index));
+            }
+        }
+        ret
+    }
+
+    fn check_ack(&mut self, data: &[u8]) -> Result<AckEvent, BeatError> {
+        let ack = Beat::from_bytes(data)?;
+        let (nonce, seq) = match ack.acknowledged() {
+            Some(a) => a,
+            None => return Err(BeatError::Malformed),
+        };
+        if nonce != self.nonce {
+            return Err(BeatError::Stale);
+        }
+        let (index, sent) = match self.pending.get(&seq) {
+            Some(&p) => p,
+            None => return Err(BeatError::Replayed),
+        };
+        BeatSendSocket::verify_ack(&self.servers[index], &self.keys[index], &ack)?;
+        self.pending.remove(&seq);
+        Ok(AckEvent::Delivered(index, sent.elapsed()))
+    }
+
+    /// Acknowledgements are either signed with the server's public key or
+    /// authenticated with one of the keys beats to the server are sent with.
+    fn verify_ack(server: &Server, keys: &KeySet, ack: &Beat) -> Result<(), BeatError> {
+        if ack.is_signed() {
+            let public_key = match server.public_key {
+                Some(ref k) => keys::verifying_key_from_hex(k).map_err(|_| BeatError::WrongChecksum)?,
+                None => return Err(BeatError::WrongChecksum),
+            };
+            return ack.verify_signature(&public_key).map(|_| ());
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        match keys.accepted(ack.key_id().as_deref(), now).into_iter().find(
+            |&(_, k)| ack.verify_beat(k).is_ok(),
+        ) {
+            Some(_) => Ok(()),
+            None => Err(BeatError::WrongChecksum),
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use super::{BeatSendSocket, IpAddr};
+    use super::*;
     use core::str::FromStr;
+    use keyring::Keyring;
+    use std::thread;
+    use testutil;
+
+    fn conf(options: &str) -> FlatConf {
+        testutil::conf(&format!("ack = true \n {}", options))
+    }
+
+    #[test]
+    fn ack_test() {
+        let listener_conf = conf("");
+        let listener = BeatListenSocket::new(&listener_conf);
+        let keyring = Keyring::new(&listener_conf).unwrap();
+        let port = listener.socket.local_addr().unwrap().port();
+        let mut sender = BeatSendSocket::new(&conf(&format!(
+            "send_status = false \n [[server]] \n address = '127.0.0.1' \n port = {} \n key = 'secret' \n",
+            port
+        )));
+
+        sender.send(0).unwrap();
+        let (beat, addr) = listener.listen().unwrap();
+        let mut beat = beat.unwrap();
+        let identity = keyring.authenticate(&mut beat).unwrap();
+        listener.acknowledge(&beat, addr, &identity).unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        match sender.receive_acks()[..] {
+            [AckEvent::Delivered(0, rtt)] => assert!(rtt < Duration::from_secs(1)),
+            ref events => panic!("unexpected events: {:?}", events),
+        }
+
+        // the same acknowledgement is only counted once
+        listener.acknowledge(&beat, addr, &identity).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert!(sender.receive_acks().is_empty());
+    }
+
+    #[test]
+    fn listen_timeout_test() {
+        let listener = BeatListenSocket::new(&conf(""));
+        let start = Instant::now();
+
+        match listener.listen() {
+            Err(BeatError::Timeout) => (),
+            other => panic!("unexpected result: {:?}", other.map(|(b, addr)| (b.err(), addr))),
+        }
+        assert!(start.elapsed() < LISTEN_TIMEOUT * 2);
+    }
+
+    #[test]
+    fn rejected_test() {
+        let listener = BeatListenSocket::new(&conf(""));
+        let port = listener.socket.local_addr().unwrap().port();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender.send_to(b"no beat", ("127.0.0.1", port)).unwrap();
+
+        match listener.listen() {
+            Ok((Err(_), addr)) => assert_eq!(addr, sender.local_addr().unwrap()),
+            other => panic!("unexpected result: {:?}", other.map(|(b, addr)| (b.is_ok(), addr))),
+        }
+    }
+
+    #[test]
+    fn lost_ack_test() {
+        let listener = BeatListenSocket::new(&conf(""));
+        let port = listener.socket.local_addr().unwrap().port();
+        let mut sender = BeatSendSocket::new(&conf(&format!(
+            "ack_timeout = 0 \n send_status = false \n [[server]] \n address = '127.0.0.1' \n \
+             port = {} \n key = 'secret' \n",
+            port
+        )));
+
+        sender.send(0).unwrap();
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(sender.receive_acks(), vec![AckEvent::Lost(0)]);
+    }
+
+    #[test]
+    fn no_valid_key_test() {
+        let listener = BeatListenSocket::new(&conf(""));
+        let port = listener.socket.local_addr().unwrap().port();
+        let mut sender = BeatSendSocket::new(&conf(&format!(
+            "send_status = false \n [[server]] \n address = '127.0.0.1' \n port = {} \n key = '' \n \
+             [[server.keys]] \n id = 'old' \n key = 'old-key' \n not_after = 2018-06-01 \n \
+             [[server.keys]] \n id = 'next' \n key = 'next-key' \n not_before = 2999-01-01 \n",
+            port
+        )));
+
+        match sender.send(0) {
+            Err(BeatError::NoKey) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match listener.listen() {
+            Err(BeatError::Timeout) => (),
+            other => panic!("unexpected result: {:?}", other.map(|(b, addr)| (b.err(), addr))),
+        }
+    }
 
     #[test]
     #[ignore]
@@ -1,13 +1,73 @@
+use flatconf::{DEFAULT_INTERVAL, DEFAULT_MISSED_BEATS, DEFAULT_PHI_DOWN, DEFAULT_PHI_SUSPECT};
+use phi::{PhiDetector, Suspicion};
 use server::Server;
+use status::Status;
 use std::fmt;
 use std::time::*;
 
+/// Health of a host as seen by the listener.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum HostState {
+    /// No beat has been received yet.
+    Unknown,
+    Up,
+    /// Beats are late enough for the failure detector to raise suspicion,
+    /// but the host has not missed `missed_beats` of them yet.
+    Suspect,
+    Down,
+    /// Beats arrive again after the host was down. Becomes `Up` once the
+    /// host stayed healthy for `offline_after`.
+    Recovered,
+}
+
+impl fmt::Display for HostState {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(match *self {
+            HostState::Unknown => "UNKNOWN",
+            HostState::Up => "UP",
+            HostState::Suspect => "SUSPECT",
+            HostState::Down => "DOWN",
+            HostState::Recovered => "RECOVERED",
+        })
+    }
+}
+
+/// A change of the state of a host.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub struct Transition {
+    pub from: HostState,
+    pub to: HostState,
+    /// Local time of the change, since the epoch.
+    pub at: Duration,
+    /// Time the host spent in the previous state.
+    pub duration: Duration,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct Statistic {
     pub send_beats: u64,
     pub recv_beats: u64,
+    pub rejected_beats: u64,
+    pub acked_beats: u64,
+    pub lost_beats: u64,
+    /// Round-trip time of the last acknowledged beat.
+    pub rtt: Option<Duration>,
     pub server: Server,
-    pub timestamp: u64
+    /// Time the last beat was sent at, since the epoch.
+    pub last_seen: Duration,
+    /// Clock offset of the host in milliseconds.
+    pub clock_offset: i64,
+    pub key_id: Option<String>,
+    pub status: Option<Status>,
+    /// Time without beats after which the host is considered offline.
+    pub offline_after: Duration,
+    /// Failure detector fed with the local arrival times of beats.
+    pub phi: PhiDetector,
+    pub state: HostState,
+    /// Local time the current state was entered at, since the epoch.
+    pub state_since: Duration,
+    pub last_transition: Option<Transition>,
 }
 
 impl fmt::Display for Statistic {
@@ -16,15 +76,38 @@ impl fmt::Display for Statistic {
         try!(fmt.write_str(&self.send_beats.to_string()));
         try!(fmt.write_str(" Rx: "));
         try!(fmt.write_str(&self.recv_beats.to_string()));
+        try!(fmt.write_str(" Rejected: "));
+        try!(fmt.write_str(&self.rejected_beats.to_string()));
+        if self.acked_beats + self.lost_beats > 0 {
+            try!(fmt.write_str(" Acked: "));
+            try!(fmt.write_str(&self.acked_beats.to_string()));
+            try!(fmt.write_str(" Lost: "));
+            try!(fmt.write_str(&self.lost_beats.to_string()));
+        }
+        if let Some(rtt) = self.rtt {
+            try!(write!(fmt, " RTT: {:.3}ms", rtt.as_secs_f64() * 1000.0));
+        }
         try!(fmt.write_str(" Host: "));
         try!(fmt.write_str(&self.server.to_string()));
-        if self.timestamp != 0 && SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs() - self.timestamp > 60 {
-            try!(fmt.write_str(" OFFLINE"));
-        } else {
-            try!(fmt.write_str(" ONLINE"));
+        try!(fmt.write_str(" Offset: "));
+        try!(write!(fmt, "{:+}ms", self.clock_offset));
+        if let Some(ref k) = self.key_id {
+            try!(fmt.write_str(" Key: "));
+            try!(fmt.write_str(k));
+        }
+        if let Some(ref s) = self.status {
+            try!(s.fmt(fmt));
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        try!(write!(fmt, " Phi: {:.2}", self.phi.phi(now)));
+        match self.phi.suspicion(now) {
+            Suspicion::Trusted => (),
+            Suspicion::Suspect => try!(fmt.write_str(" (suspect)")),
+            Suspicion::Down => try!(fmt.write_str(" (down)")),
+        }
+        try!(write!(fmt, " {}", self.state));
+        if self.state != HostState::Unknown {
+            try!(write!(fmt, " for {}s", self.time_in_state(now).as_secs()));
         }
         try!(fmt.write_str("\n"));
         Ok(())
@@ -36,8 +119,24 @@ impl Statistic {
         Statistic {
             send_beats: 0,
             recv_beats: 0,
-            server: s.clone(),
-            timestamp: 0,
+            rejected_beats: 0,
+            acked_beats: 0,
+            lost_beats: 0,
+            rtt: None,
+            server: s.without_keys(),
+            last_seen: Duration::from_secs(0),
+            clock_offset: 0,
+            key_id: None,
+            status: None,
+            offline_after: Duration::from_secs(DEFAULT_INTERVAL * DEFAULT_MISSED_BEATS),
+            phi: PhiDetector::new(
+                DEFAULT_PHI_SUSPECT,
+                DEFAULT_PHI_DOWN,
+                Duration::from_secs(DEFAULT_INTERVAL),
+            ),
+            state: HostState::Unknown,
+            state_since: SystemTime::now().duration_since(UNIX_EPOCH).unwrap(),
+            last_transition: None,
         }
     }
 
@@ -49,16 +148,294 @@ impl Statistic {
         self.recv_beats += 1;
     }
 
-    pub fn set_timestamp(&mut self, timestamp: u64) {
-        self.timestamp = timestamp;
+    pub fn incr_rejected(&mut self) {
+        self.rejected_beats += 1;
+    }
+
+    pub fn incr_acked(&mut self) {
+        self.acked_beats += 1;
+    }
+
+    pub fn incr_lost(&mut self) {
+        self.lost_beats += 1;
+    }
+
+    pub fn set_rtt(&mut self, rtt: Duration) {
+        self.rtt = Some(rtt);
+    }
+
+    pub fn set_last_seen(&mut self, last_seen: Duration) {
+        self.last_seen = last_seen;
+    }
+
+    pub fn set_clock_offset(&mut self, offset: i64) {
+        self.clock_offset = offset;
     }
 
-    pub fn is_offline(&self) -> bool {
-        if self.timestamp != 0 && SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
-            .as_secs() - self.timestamp > 60 {
-            true
-        } else {
-            false
+    pub fn set_key_id(&mut self, key_id: Option<String>) {
+        self.key_id = key_id;
+    }
+
+    pub fn set_status(&mut self, status: Option<Status>) {
+        self.status = status;
+    }
+
+    pub fn set_detector(&mut self, phi: PhiDetector) {
+        self.phi = phi;
+    }
+
+    /// Feeds the failure detector with the local time a beat arrived at.
+    pub fn record_arrival(&mut self, arrival: Duration) {
+        self.phi.record(arrival);
+    }
+
+    pub fn set_offline_after(&mut self, offline_after: Duration) {
+        self.offline_after = offline_after;
+    }
+
+    /// Whether no beat arrived for `offline_after` at the given local time.
+    /// Only local arrival times count, the clock of the host may be off.
+    pub fn is_offline(&self, now: Duration) -> bool {
+        match self.phi.last_arrival().and_then(|a| now.checked_sub(a)) {
+            Some(d) => d > self.offline_after,
+            None => false,
         }
     }
+
+    /// Time spent in the current state at the given local time.
+    pub fn time_in_state(&self, now: Duration) -> Duration {
+        now.checked_sub(self.state_since).unwrap_or_default()
+    }
+
+    /// Only missing beats for `offline_after` takes a host down. The failure
+    /// detector merely raises suspicion before that, and keeps a host that
+    /// was down there until its beats look regular again.
+    fn next_state(&self, now: Duration) -> HostState {
+        if self.recv_beats == 0 {
+            return HostState::Unknown;
+        }
+        if self.is_offline(now) {
+            return HostState::Down;
+        }
+        match (self.phi.suspicion(now), self.state) {
+            (Suspicion::Suspect, HostState::Down) |
+            (Suspicion::Down, HostState::Down) => HostState::Down,
+            (Suspicion::Suspect, _) |
+            (Suspicion::Down, _) => HostState::Suspect,
+            (Suspicion::Trusted, HostState::Down) => HostState::Recovered,
+            (Suspicion::Trusted, HostState::Recovered) => {
+                if self.time_in_state(now) >= self.offline_after {
+                    HostState::Up
+                } else {
+                    HostState::Recovered
+                }
+            }
+            (Suspicion::Trusted, _) => HostState::Up,
+        }
+    }
+
+    /// Moves the host to the state it is in at the given local time and
+    /// returns the transition, if any.
+    pub fn update(&mut self, now: Duration) -> Option<Transition> {
+        let next = self.next_state(now);
+        self.enter(next, now)
+    }
+
+    /// Moves the host to the given state at the given local time and
+    /// returns the transition, if it was in another one.
+    pub fn enter(&mut self, next: HostState, now: Duration) -> Option<Transition> {
+        if next == self.state {
+            return None;
+        }
+        let transition = Transition {
+            from: self.state,
+            to: next,
+            at: now,
+            duration: self.time_in_state(now),
+        };
+        self.state = next;
+        self.state_since = now;
+        self.last_transition = Some(transition);
+        Some(transition)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testutil::server;
+
+    fn now() -> Duration {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
+    }
+
+    #[test]
+    fn future_timestamp_test() {
+        let mut s = Statistic::new(&server());
+        s.record_arrival(now());
+        s.set_last_seen(now() + Duration::from_secs(3600));
+        s.set_clock_offset(3_600_000);
+
+        assert!(!s.is_offline(now()));
+        assert!(s.to_string().contains("Offset: +3600000ms"));
+    }
+
+    #[test]
+    fn offline_test() {
+        let mut s = Statistic::new(&server());
+        assert!(!s.is_offline(now()));
+
+        s.record_arrival(now() - Duration::from_millis(120_500));
+        assert!(s.is_offline(now()));
+    }
+
+    #[test]
+    fn clock_behind_test() {
+        let mut s = Statistic::new(&server());
+        s.set_offline_after(Duration::from_secs(5));
+        s.set_detector(PhiDetector::new(5.0, 8.0, Duration::from_secs(1)));
+        let start = now();
+        for i in 0..20 {
+            // the clock of the host is 10 s behind
+            s.incr_recv();
+            s.record_arrival(start + Duration::from_secs(i));
+            s.set_last_seen(start + Duration::from_secs(i) - Duration::from_secs(10));
+            s.update(start + Duration::from_secs(i) + Duration::from_millis(100));
+            assert_eq!(s.state, HostState::Up);
+        }
+    }
+
+    #[test]
+    fn status_test() {
+        let mut s = Statistic::new(&server());
+        s.set_status(Some(Status {
+            message: Some("maintenance".to_string()),
+            ..Status::default()
+        }));
+
+        assert!(s.to_string().contains(" Status: maintenance"));
+    }
+
+    #[test]
+    fn ack_test() {
+        let mut s = Statistic::new(&server());
+        assert!(!s.to_string().contains("Acked"));
+
+        s.incr_acked();
+        s.incr_lost();
+        s.set_rtt(Duration::from_micros(1500));
+        assert!(s.to_string().contains(" Acked: 1 Lost: 1 RTT: 1.500ms"));
+    }
+
+    #[test]
+    fn offline_after_test() {
+        let mut s = Statistic::new(&server());
+        s.set_offline_after(Duration::from_secs(5));
+
+        s.record_arrival(now() - Duration::from_secs(3));
+        assert!(!s.is_offline(now()));
+        assert!(s.is_offline(now() + Duration::from_secs(3)));
+    }
+
+    #[test]
+    fn unknown_test() {
+        let mut s = Statistic::new(&server());
+        assert_eq!(s.update(now()), None);
+        assert_eq!(s.state, HostState::Unknown);
+        assert!(s.to_string().ends_with(" UNKNOWN\n"));
+    }
+
+    #[test]
+    fn state_test() {
+        let mut s = Statistic::new(&server());
+        s.set_offline_after(Duration::from_secs(60));
+        let start = now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+        for i in 0..5 {
+            s.incr_recv();
+            s.record_arrival(at(i * 1000));
+        }
+        s.set_last_seen(now());
+
+        let t = s.update(at(4500)).unwrap();
+        assert_eq!((t.from, t.to), (HostState::Unknown, HostState::Up));
+        assert_eq!(s.update(at(4600)), None);
+
+        assert_eq!(s.update(at(5450)), None);
+        assert_eq!(s.update(at(6100)).unwrap().to, HostState::Suspect);
+        // however late the beats, only offline_after takes the host down
+        assert_eq!(s.update(at(7500)), None);
+        assert_eq!(s.update(at(64_000)), None);
+        let t = s.update(at(64_100)).unwrap();
+        assert_eq!((t.from, t.to), (HostState::Suspect, HostState::Down));
+        assert_eq!(t.duration, Duration::from_millis(58_000));
+
+        s.record_arrival(at(65_000));
+        assert_eq!(s.update(at(65_100)).unwrap().to, HostState::Recovered);
+        assert_eq!(s.update(at(66_000)), None);
+        assert!(s.to_string().contains(" RECOVERED for "));
+
+        for i in 66..126 {
+            s.record_arrival(at(i * 1000));
+        }
+        let t = s.update(at(125_100)).unwrap();
+        assert_eq!((t.from, t.to), (HostState::Recovered, HostState::Up));
+        assert_eq!(t.duration, Duration::from_secs(60));
+    }
+
+    #[test]
+    fn enter_test() {
+        let mut s = Statistic::new(&server());
+        let start = now();
+
+        assert_eq!(s.enter(HostState::Down, start).unwrap().from, HostState::Unknown);
+        assert_eq!(s.enter(HostState::Down, start + Duration::from_secs(10)), None);
+        let t = s.enter(HostState::Recovered, start + Duration::from_secs(30)).unwrap();
+        assert_eq!((t.from, t.duration), (HostState::Down, Duration::from_secs(30)));
+        assert_eq!(s.state_since, start + Duration::from_secs(30));
+    }
+
+    #[test]
+    fn offline_state_test() {
+        let mut s = Statistic::new(&server());
+        s.set_offline_after(Duration::from_secs(5));
+        s.incr_recv();
+        s.record_arrival(now() - Duration::from_secs(6));
+
+        assert_eq!(s.update(now()).unwrap().to, HostState::Down);
+        assert!(s.to_string().contains(" DOWN for 0s"));
+    }
+
+    #[test]
+    fn phi_test() {
+        let mut s = Statistic::new(&server());
+        assert!(s.to_string().contains(" Phi: 0.00 "));
+
+        let start = now() - Duration::from_secs(30);
+        for i in 0..10 {
+            s.record_arrival(start + Duration::from_secs(i));
+        }
+        s.incr_recv();
+        s.update(now());
+        assert_eq!(s.state, HostState::Suspect);
+        assert!(s.to_string().contains(" (down) SUSPECT for "));
+    }
+
+    #[test]
+    fn missed_beat_test() {
+        let mut s = Statistic::new(&server());
+        let start = now() - Duration::from_secs(10);
+        for i in 0..9 {
+            s.incr_recv();
+            s.record_arrival(start + Duration::from_secs(i));
+        }
+        s.set_last_seen(start + Duration::from_secs(8));
+        assert_eq!(s.update(start + Duration::from_secs(8)).unwrap().to, HostState::Up);
+
+        // the beat due a second ago was lost
+        let late = start + Duration::from_millis(10_500);
+        assert_eq!(s.phi.suspicion(late), Suspicion::Down);
+        assert_eq!(s.update(late).unwrap().to, HostState::Suspect);
+        assert_eq!(s.update(late + Duration::from_secs(5)), None);
+    }
 }
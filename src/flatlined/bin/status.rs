@@ -0,0 +1,246 @@
+use beat::*;
+use flatconf::FlatConf;
+use nix::sys::statvfs;
+use std::fmt;
+use std::fs::File;
+use std::io::prelude::*;
+use std::str;
+
+/// Load averages over 1, 5 and 15 minutes, 3 times 4 bytes in hundredths.
+pub const STATUS_LOAD: u8 = 1;
+/// Uptime of the sender in seconds, 8 bytes.
+pub const STATUS_UPTIME: u8 = 2;
+/// UTF-8 version of the sending daemon.
+pub const STATUS_VERSION: u8 = 3;
+/// Free disk space in bytes, 8 bytes.
+pub const STATUS_DISK_FREE: u8 = 4;
+/// UTF-8 status message set by the operator.
+pub const STATUS_MESSAGE: u8 = 5;
+
+/// Longest status message accepted in the configuration.
+pub const MAX_STATUS_MESSAGE: usize = 255;
+
+static VERSION: &'static str = env!("CARGO_PKG_VERSION");
+
+/// Health status a sender attaches to its beats.
+///
+/// It is encoded as the value of an `EXT_STATUS` extension which itself
+/// holds a sequence of extensions, one per field. Fields that could not be
+/// determined are left out, unknown fields are skipped.
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+pub struct Status {
+    pub load: Option<[f32; 3]>,
+    pub uptime: Option<u64>,
+    pub version: Option<String>,
+    pub disk_free: Option<u64>,
+    pub message: Option<String>,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(l) = self.load {
+            try!(write!(fmt, " Load: {:.2} {:.2} {:.2}", l[0], l[1], l[2]));
+        }
+        if let Some(u) = self.uptime {
+            try!(write!(fmt, " Up: {}d{:02}h{:02}m", u / 86_400, u % 86_400 / 3600, u % 3600 / 60));
+        }
+        if let Some(ref v) = self.version {
+            try!(fmt.write_str(" Version: "));
+            try!(fmt.write_str(v));
+        }
+        if let Some(d) = self.disk_free {
+            try!(write!(fmt, " Disk: {}M", d / (1024 * 1024)));
+        }
+        if let Some(ref m) = self.message {
+            try!(fmt.write_str(" Status: "));
+            try!(fmt.write_str(m));
+        }
+        Ok(())
+    }
+}
+
+fn read_proc(path: &str) -> Option<String> {
+    let mut buffer = String::new();
+    match File::open(path).and_then(|mut f| f.read_to_string(&mut buffer)) {
+        Ok(_) => Some(buffer),
+        Err(_) => None,
+    }
+}
+
+fn string_field(value: &[u8]) -> Option<String> {
+    str::from_utf8(value).ok().map(|v| v.to_string())
+}
+
+fn u64_field(value: &[u8]) -> Option<u64> {
+    if value.len() != 8 {
+        return None;
+    }
+    let mut v = [0u8; 8];
+    v[..].clone_from_slice(value);
+    Some(u8arr_to_u64(v))
+}
+
+impl Status {
+    /// Gathers the status of the local host.
+    pub fn collect(conf: &FlatConf) -> Status {
+        let path = conf.status_path.clone().unwrap_or_else(|| "/".to_string());
+        Status {
+            load: read_proc("/proc/loadavg").and_then(|l| Status::parse_load(&l)),
+            uptime: read_proc("/proc/uptime").and_then(|u| Status::parse_uptime(&u)),
+            version: Some(VERSION.to_string()),
+            disk_free: statvfs::statvfs(path.as_str()).ok().map(|s| {
+                s.blocks_available() * s.fragment_size()
+            }),
+            message: conf.status.clone(),
+        }
+    }
+
+    fn parse_load(input: &str) -> Option<[f32; 3]> {
+        let values: Vec<f32> = input
+            .split_whitespace()
+            .take(3)
+            .filter_map(|v| v.parse().ok())
+            .collect();
+        if values.len() == 3 {
+            Some([values[0], values[1], values[2]])
+        } else {
+            None
+        }
+    }
+
+    fn parse_uptime(input: &str) -> Option<u64> {
+        input
+            .split_whitespace()
+            .next()
+            .and_then(|u| u.parse::<f64>().ok())
+            .map(|u| u as u64)
+    }
+
+    pub fn to_extension(&self) -> Extension {
+        let mut fields = Vec::new();
+        if let Some(l) = self.load {
+            let mut value = Vec::new();
+            for v in &l {
+                value.extend_from_slice(&u32_to_u8arr((v * 100.0).round() as u32));
+            }
+            fields.push(Extension {
+                typ: STATUS_LOAD,
+                value,
+            });
+        }
+        if let Some(u) = self.uptime {
+            fields.push(Extension {
+                typ: STATUS_UPTIME,
+                value: u64_to_u8arr(u).to_vec(),
+            });
+        }
+        if let Some(ref v) = self.version {
+            fields.push(Extension {
+                typ: STATUS_VERSION,
+                value: v.as_bytes().to_vec(),
+            });
+        }
+        if let Some(d) = self.disk_free {
+            fields.push(Extension {
+                typ: STATUS_DISK_FREE,
+                value: u64_to_u8arr(d).to_vec(),
+            });
+        }
+        if let Some(ref m) = self.message {
+            fields.push(Extension {
+                typ: STATUS_MESSAGE,
+                value: m.as_bytes().to_vec(),
+            });
+        }
+        Extension {
+            typ: EXT_STATUS,
+            value: encode_extensions(&fields),
+        }
+    }
+
+    /// Returns the status carried by an opened beat. Fields with a wrong
+    /// size are ignored.
+    pub fn from_beat(beat: &Beat) -> Option<Status> {
+        let fields = match beat.extension(EXT_STATUS) {
+            Some(e) => Beat::parse_extensions(&e.value).ok()?,
+            None => return None,
+        };
+        let mut ret = Status::default();
+        for f in fields {
+            match f.typ {
+                STATUS_LOAD if f.value.len() == 12 => {
+                    let mut load = [0f32; 3];
+                    for (i, chunk) in f.value.chunks(4).enumerate() {
+                        let mut v = [0u8; 4];
+                        v[..].clone_from_slice(chunk);
+                        load[i] = u8arr_to_u32(v) as f32 / 100.0;
+                    }
+                    ret.load = Some(load);
+                }
+                STATUS_UPTIME => ret.uptime = u64_field(&f.value),
+                STATUS_VERSION => ret.version = string_field(&f.value),
+                STATUS_DISK_FREE => ret.disk_free = u64_field(&f.value),
+                STATUS_MESSAGE => ret.message = string_field(&f.value),
+                _ => (),
+            }
+        }
+        Some(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status() -> Status {
+        Status {
+            load: Some([0.5, 1.25, 12.0]),
+            uptime: Some(93_784),
+            version: Some("0.1.0".to_string()),
+            disk_free: Some(2 * 1024 * 1024 * 1024),
+            message: Some("degraded".to_string()),
+        }
+    }
+
+    #[test]
+    fn round_trip_test() {
+        let beat = Beat::with_extensions("foo", vec![status().to_extension()]);
+        let beat = Beat::from_bytes(&beat.into_bytes()).unwrap();
+
+        assert_eq!(Status::from_beat(&beat), Some(status()));
+        assert!(Status::from_beat(&Beat::new("foo")).is_none());
+    }
+
+    #[test]
+    fn partial_test() {
+        let partial = Status {
+            uptime: Some(1),
+            ..Status::default()
+        };
+        let mut ext = partial.to_extension();
+        // unknown field
+        ext.value.extend_from_slice(&[200, 1, 0, 42]);
+        let beat = Beat::with_extensions("foo", vec![ext]);
+
+        assert_eq!(Status::from_beat(&beat), Some(partial));
+    }
+
+    #[test]
+    fn parse_proc_test() {
+        assert_eq!(
+            Status::parse_load("0.52 0.48 0.40 1/123 4567\n"),
+            Some([0.52, 0.48, 0.40])
+        );
+        assert!(Status::parse_load("").is_none());
+        assert_eq!(Status::parse_uptime("93784.21 180000.00\n"), Some(93_784));
+    }
+
+    #[test]
+    fn display_test() {
+        assert_eq!(
+            status().to_string(),
+            " Load: 0.50 1.25 12.00 Up: 1d02h03m Version: 0.1.0 Disk: 2048M Status: degraded"
+        );
+        assert_eq!(Status::default().to_string(), "");
+    }
+}
@@ -0,0 +1,56 @@
+use alert::{Alert, AlertKind, AlertSeverity};
+use flatconf::FlatConf;
+use keys::Secret;
+use server::Server;
+use stats::HostState;
+
+/// Parses a configuration with the required options set and `extra`
+/// appended.
+pub fn parse(extra: &str) -> Result<FlatConf, String> {
+    let mut input = format!(
+        "port = 0 \n logfile = '' \n key = 'secret' \n verbose = false \n {}",
+        extra
+    );
+    FlatConf::parse(&mut input)
+}
+
+pub fn conf(extra: &str) -> FlatConf {
+    parse(extra).unwrap()
+}
+
+/// A server at 10.0.0.1:8888 with nothing but a key configured.
+pub fn server() -> Server {
+    Server {
+        address: "10.0.0.1".to_string(),
+        port: 8888,
+        key: Secret::new("foo".to_string()),
+        key_file: None,
+        key_env: None,
+        node_id: None,
+        keys: None,
+        public_key: None,
+        interval: None,
+        missed_beats: None,
+        tags: None,
+        severity: None,
+    }
+}
+
+/// A critical down alert for web1, tagged web.
+pub fn alert() -> Alert {
+    Alert {
+        kind: AlertKind::Down,
+        host: "web1".to_string(),
+        address: "10.0.0.1".to_string(),
+        node_id: Some("web1".to_string()),
+        state: HostState::Down,
+        previous_state: Some(HostState::Up),
+        last_seen: 1000,
+        downtime: 0,
+        send_beats: 0,
+        recv_beats: 42,
+        rejected_beats: 1,
+        severity: AlertSeverity::Critical,
+        tags: vec!["web".to_string()],
+    }
+}
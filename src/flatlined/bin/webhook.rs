@@ -0,0 +1,196 @@
+use alert::{Alert, Digest, Notification};
+use executor::{DEFAULT_ALERT_RETRIES, DEFAULT_ALERT_TIMEOUT};
+use flatconf::FlatConf;
+use queue::{Queue, RETRY_DELAY};
+use sink::AlertSink;
+use std::time::Duration;
+use ureq;
+
+/// Posts alerts and digests as JSON documents to a URL.
+///
+/// Failed deliveries are retried `alert_retries` times. The URL often carries
+/// a token, so it is kept out of the log and the sink goes by its name.
+pub struct Webhook {
+    name: String,
+    queue: Queue,
+}
+
+impl Webhook {
+    pub fn new(name: &str, url: &str, conf: &FlatConf) -> Webhook {
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(
+                conf.alert_timeout.unwrap_or(DEFAULT_ALERT_TIMEOUT),
+            ))
+            .build();
+        Webhook::start(
+            name,
+            url,
+            agent,
+            conf.alert_retries.unwrap_or(DEFAULT_ALERT_RETRIES),
+            RETRY_DELAY,
+        )
+    }
+
+    fn start(
+        name: &str,
+        url: &str,
+        agent: ureq::Agent,
+        retries: u32,
+        retry_delay: Duration,
+    ) -> Webhook {
+        let target = url.to_string();
+        Webhook {
+            name: name.to_string(),
+            queue: Queue::start(name.to_string(), retries, retry_delay, move |notification| {
+                post(&agent, &target, notification)
+            }),
+        }
+    }
+}
+
+impl AlertSink for Webhook {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn send(&self, alert: &Alert) {
+        self.queue.send(Notification::Alert(alert.clone()));
+    }
+
+    fn send_digest(&self, digest: &Digest) {
+        self.queue.send(Notification::Digest(digest.clone()));
+    }
+}
+
+/// Errors of ureq start with the URL and are described without it.
+fn post(agent: &ureq::Agent, url: &str, notification: &Notification) -> Result<(), String> {
+    match agent.post(url).send_json(notification) {
+        Ok(_) => Ok(()),
+        Err(ureq::Error::Status(code, _)) => Err(format!("status code {}", code)),
+        Err(ureq::Error::Transport(t)) => Err(match t.message() {
+            Some(m) => format!("{}: {}", t.kind(), m),
+            None => t.kind().to_string(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{self, Value};
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::{self, Receiver};
+    use std::thread;
+    use testutil::alert;
+
+    /// Answers requests with the given status codes, one connection per
+    /// request, and passes the bodies on.
+    fn stand_in(codes: Vec<u16>) -> (String, Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || for code in codes {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                if let Some(l) = line.to_lowercase().strip_prefix("content-length:") {
+                    length = l.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            write!(
+                reader.get_mut(),
+                "HTTP/1.1 {} Status\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                code
+            ).unwrap();
+            tx.send(String::from_utf8(body).unwrap()).unwrap();
+        });
+        (url, rx)
+    }
+
+    fn webhook(url: &str, retries: u32) -> Webhook {
+        let agent = ureq::AgentBuilder::new().timeout(Duration::from_secs(5)).build();
+        Webhook::start("webhook", url, agent, retries, Duration::from_millis(10))
+    }
+
+    #[test]
+    fn json_test() {
+        let (url, rx) = stand_in(vec![200]);
+        webhook(&url, 0).send(&alert());
+
+        let body: Value = serde_json::from_str(&rx.recv_timeout(Duration::from_secs(5)).unwrap())
+            .unwrap();
+        assert_eq!(body["alert"], "down");
+        assert_eq!(body["host"], "web1");
+        assert_eq!(body["state"], "DOWN");
+        assert_eq!(body["previous_state"], "UP");
+        assert_eq!(body["recv_beats"], 42);
+        assert_eq!(body["severity"], "critical");
+        assert_eq!(body["tags"][0], "web");
+    }
+
+    #[test]
+    fn retry_test() {
+        let (url, rx) = stand_in(vec![500, 503, 200, 200]);
+        let hook = webhook(&url, 2);
+        hook.send(&alert());
+        hook.send(&alert());
+
+        for _ in 0..4 {
+            assert!(rx.recv_timeout(Duration::from_secs(5)).is_ok());
+        }
+    }
+
+    #[test]
+    fn give_up_test() {
+        let (url, rx) = stand_in(vec![500, 500, 200]);
+        let hook = webhook(&url, 1);
+        let mut second = alert();
+        second.host = "web2".to_string();
+        second.node_id = None;
+        hook.send(&alert());
+        hook.send(&second);
+
+        // the first alert is dropped after two attempts, the second is
+        // delivered on its first one
+        for _ in 0..2 {
+            assert!(rx.recv_timeout(Duration::from_secs(5)).unwrap().contains("web1"));
+        }
+        assert!(rx.recv_timeout(Duration::from_secs(5)).unwrap().contains("web2"));
+    }
+
+    #[test]
+    fn error_test() {
+        let (url, _rx) = stand_in(vec![500]);
+        let agent = ureq::AgentBuilder::new().timeout(Duration::from_secs(5)).build();
+        let secret = format!("{}/T0KEN", url);
+
+        let e = post(&agent, &secret, &Notification::Alert(alert())).unwrap_err();
+        assert_eq!(e, "status code 500");
+        let e = post(&agent, "http://127.0.0.1:1/T0KEN", &Notification::Alert(alert()))
+            .unwrap_err();
+        assert!(!e.contains("T0KEN"));
+    }
+
+    #[test]
+    fn digest_test() {
+        let (url, rx) = stand_in(vec![200]);
+        let mut second = alert();
+        second.host = "web2".to_string();
+        webhook(&url, 0).send_digest(&Digest::new(vec![alert(), second]));
+
+        let body: Value = serde_json::from_str(&rx.recv_timeout(Duration::from_secs(5)).unwrap())
+            .unwrap();
+        assert_eq!(body["digest"], "down");
+        assert_eq!(body["count"], 2);
+        assert_eq!(body["alerts"][1]["host"], "web2");
+    }
+}
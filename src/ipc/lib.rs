@@ -22,6 +22,8 @@ pub enum IPCMsgType {
     Ok,
     Error,
     Any,
+    /// Part of a reply that continues in the next message.
+    More,
 }
 
 pub struct IPCMsg {
@@ -31,6 +33,32 @@ pub struct IPCMsg {
 
 pub type IPCMsgPayloadResult = Result<usize, std::string::String>;
 
+/// Maximum size of an IPC message payload in bytes.
+pub const PAYLOAD_SIZE: usize = 1024;
+
+/// Splits a reply into payloads of at most `PAYLOAD_SIZE` bytes, at line
+/// ends where possible. All but the last are meant to be sent as
+/// `IPCMsgType::More`.
+pub fn split_payload(msg: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut rest = msg;
+    while rest.len() > PAYLOAD_SIZE {
+        let mut end = PAYLOAD_SIZE;
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        if let Some(i) = rest[..end].rfind('\n') {
+            end = i + 1;
+        }
+        parts.push(&rest[..end]);
+        rest = &rest[end..];
+    }
+    if !rest.is_empty() {
+        parts.push(rest);
+    }
+    parts
+}
+
 /// # Example
 ///
 /// ```
@@ -57,6 +85,8 @@ impl IPCMsg {
         let mut i = 0;
         if msg.is_empty() {
             Err("Can't send empty message!".to_string())
+        } else if msg.len() > PAYLOAD_SIZE {
+            Err("Message too long!".to_string())
         } else {
             for c in msg.as_bytes() {
                 ret[i] = *c;
@@ -186,6 +216,8 @@ impl IPC {
                     2 => ret.typ = IPCMsgType::Statistic,
                     3 => ret.typ = IPCMsgType::Quit,
                     4 => ret.typ = IPCMsgType::Ok,
+                    5 => ret.typ = IPCMsgType::Error,
+                    7 => ret.typ = IPCMsgType::More,
                     _ => ret.typ = IPCMsgType::Any,
                 };
                 ret.msg.clone_from_slice(&buffer[1..1025]);
@@ -286,6 +318,32 @@ mod ipc {
         quickcheck(qc as fn(Vec<u8>) -> bool);
     }
 
+    #[test]
+    fn payload_size_test() {
+        let mut msg = IPCMsg {
+            typ: IPCMsgType::Status,
+            msg: [0u8; 1024],
+        };
+
+        assert_eq!(msg.create_payload(&"x".repeat(PAYLOAD_SIZE)), Ok(PAYLOAD_SIZE));
+        assert!(msg.create_payload(&"x".repeat(PAYLOAD_SIZE + 1)).is_err());
+    }
+
+    #[test]
+    fn split_payload_test() {
+        let line = format!("{}\n", "x".repeat(400));
+        let reply = line.repeat(5);
+        let parts = split_payload(&reply);
+
+        assert_eq!(parts, vec![line.repeat(2), line.repeat(2), line.clone()]);
+        assert_eq!(split_payload("short"), vec!["short"]);
+
+        let long = "\u{e4}".repeat(PAYLOAD_SIZE);
+        let parts = split_payload(&long);
+        assert!(parts.iter().all(|p| p.len() <= PAYLOAD_SIZE));
+        assert_eq!(parts.concat(), long);
+    }
+
     #[test]
     #[should_panic]
     fn socket_bind_panic_test() {